@@ -1,5 +1,7 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand};
+use safefile::container::{Cipher, RecipientKind};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[macro_export]
 macro_rules! progress_style {
@@ -17,7 +19,7 @@ macro_rules! progress_style {
 #[command(
     author,
     version,
-    about = "Encrypt files with AES256-GCM and split key with SSS"
+    about = "Encrypt files with AES-256-GCM, ChaCha20-Poly1305 or AES-256-GCM-SIV and split key with SSS"
 )]
 pub struct Cli {
     #[command(subcommand)]
@@ -30,6 +32,7 @@ pub enum Commands {
         about = "Encrypt a file and split the key into shares (default 5 shares, threshold 3)"
     )]
     Encrypt {
+        #[arg(help = "File or directory to encrypt, or - to read from stdin")]
         input: PathBuf,
         output: PathBuf,
         #[arg(short, long, default_value_t = 5)]
@@ -40,13 +43,345 @@ pub enum Commands {
         outdir: Option<PathBuf>,
         #[arg(short = 'l', long)]
         label: Option<String>,
+        #[arg(
+            short,
+            long,
+            conflicts_with_all = ["shares", "threshold", "outdir", "recipients"],
+            help = "Protect the file with a password instead of key shares (read from SAFEFILE_PASSWORD or prompted)"
+        )]
+        password: bool,
+        #[arg(
+            short,
+            long = "recipient",
+            value_name = "PUBKEY",
+            conflicts_with = "shares",
+            help = "Seal one share to this public key or .pub file; repeat once per holder"
+        )]
+        recipients: Vec<String>,
+        #[arg(
+            short,
+            long,
+            requires = "recipients",
+            conflicts_with = "outdir",
+            help = "Store the sealed shares inside the safe file instead of writing share files"
+        )]
+        embed: bool,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Worker threads encrypting chunks in parallel, 0 for one per core"
+        )]
+        threads: usize,
+        #[arg(
+            long,
+            default_value_t = Cipher::Aes256Gcm,
+            help = "Cipher to encrypt with: aes-256-gcm, chacha20-poly1305 (fast without AES instructions) or aes-256-gcm-siv (nonce-misuse resistant)"
+        )]
+        cipher: Cipher,
+        #[arg(
+            long,
+            value_name = "LEVEL",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "3",
+            value_parser = clap::value_parser!(i32).range(1..=22),
+            help = "Compress the input with zstd before encrypting (--compress=LEVEL, 1-22, default 3); skipped for input that does not compress"
+        )]
+        compress: Option<i32>,
     },
-    #[command(about = "Decrypt a safe file using provided share files")]
+    #[command(about = "Decrypt a safe file using provided share files or a password")]
     Decrypt {
         input: PathBuf,
+        #[arg(help = "File to write, or - to write to stdout; archives are restored into a new directory, or written to stdout as tar")]
         output: PathBuf,
         shares: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "shares",
+            help = "Decrypt with a password (read from SAFEFILE_PASSWORD or prompted)"
+        )]
+        password: bool,
+        #[arg(
+            short,
+            long = "identity",
+            value_name = "FILE",
+            conflicts_with = "password",
+            help = "Identity file to open sealed shares with (repeatable)"
+        )]
+        identities: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            conflicts_with_all = ["shares", "password", "identities"],
+            help = "Decrypt with share mnemonics read from stdin, separated by empty lines"
+        )]
+        mnemonic: bool,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Worker threads decrypting chunks in parallel, 0 for one per core"
+        )]
+        threads: usize,
+        #[arg(
+            long,
+            value_name = "START:LEN",
+            help = "Decrypt only LEN bytes from byte START, reading just the chunks that hold them (not for compressed files)"
+        )]
+        range: Option<ByteRange>,
+    },
+    #[command(about = "List the entries of an archive safe file")]
+    List {
+        input: PathBuf,
+        shares: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "shares",
+            help = "Unlock with a password (read from SAFEFILE_PASSWORD or prompted)"
+        )]
+        password: bool,
+        #[arg(
+            short,
+            long = "identity",
+            value_name = "FILE",
+            conflicts_with = "password",
+            help = "Identity file to open sealed shares with (repeatable)"
+        )]
+        identities: Vec<PathBuf>,
+    },
+    #[command(about = "Decrypt one file of an archive safe file")]
+    Extract {
+        input: PathBuf,
+        #[arg(help = "Path of the file in the archive")]
+        path: String,
+        shares: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            help = "File to write, or - to write to stdout (default: the file's name in the current directory)"
+        )]
+        output: Option<PathBuf>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "shares",
+            help = "Unlock with a password (read from SAFEFILE_PASSWORD or prompted)"
+        )]
+        password: bool,
+        #[arg(
+            short,
+            long = "identity",
+            value_name = "FILE",
+            conflicts_with = "password",
+            help = "Identity file to open sealed shares with (repeatable)"
+        )]
+        identities: Vec<PathBuf>,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Worker threads decrypting chunks in parallel, 0 for one per core"
+        )]
+        threads: usize,
     },
     #[command(about = "Inspect a safe file and display its metadata")]
     Info { input: PathBuf },
+    #[command(about = "Generate a share holder identity and write its public key to <OUTPUT>.pub")]
+    Keygen {
+        output: PathBuf,
+        #[arg(
+            short,
+            long,
+            default_value_t = RecipientKind::X25519,
+            help = "Key type: x25519, or mlkem768x25519 for a hybrid with post-quantum ML-KEM-768"
+        )]
+        kind: RecipientKind,
+    },
+    #[command(about = "Write an ASCII-armored copy of a small safe file")]
+    Armor { input: PathBuf, output: PathBuf },
+    #[command(about = "Write the binary form of an ASCII-armored safe file")]
+    Dearmor { input: PathBuf, output: PathBuf },
+    #[command(subcommand, about = "List, add and remove keyslots of a safe file")]
+    Keyslot(KeyslotCommands),
+    #[command(subcommand, about = "Convert share files to other encodings")]
+    Share(ShareCommands),
+}
+
+/// Byte range of the plaintext, given as `START:LEN`.
+#[derive(Clone, Copy, Debug)]
+pub struct ByteRange {
+    pub start: u64,
+    pub len: u64,
+}
+
+impl FromStr for ByteRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, len) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected START:LEN, got '{}'", s))?;
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid byte count '{}': {}", v, e))
+        };
+        Ok(ByteRange {
+            start: parse(start)?,
+            len: parse(len)?,
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ShareCommands {
+    #[command(
+        about = "Print a share in a transcribable encoding",
+        group(ArgGroup::new("encoding").required(true).args(["mnemonic", "armor"]))
+    )]
+    Export {
+        share: PathBuf,
+        #[arg(short, long, help = "Print the share as a list of words")]
+        mnemonic: bool,
+        #[arg(short, long, help = "Print the share as ASCII-armored text")]
+        armor: bool,
+        #[arg(
+            short,
+            long = "identity",
+            value_name = "FILE",
+            conflicts_with = "armor",
+            help = "Identity file to open a sealed share with (repeatable)"
+        )]
+        identities: Vec<PathBuf>,
+    },
+    #[command(about = "Render a share as a QR code with a caption for printing")]
+    Qr {
+        share: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Image file to write, .png or .svg (default: print to the terminal)"
+        )]
+        output: Option<PathBuf>,
+    },
+    #[command(about = "Write a printable HTML sheet per share for handing it to its holder")]
+    Sheet {
+        #[arg(required = true)]
+        shares: Vec<PathBuf>,
+        #[arg(short, long, help = "Directory to write the sheets to")]
+        outdir: PathBuf,
+        #[arg(
+            long = "holder",
+            value_name = "NAME",
+            help = "Name of a share's holder, in the order of the shares (repeatable)"
+        )]
+        holders: Vec<String>,
+        #[arg(
+            short,
+            long = "identity",
+            value_name = "FILE",
+            help = "Identity file to open sealed shares with (repeatable)"
+        )]
+        identities: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyslotCommands {
+    #[command(about = "List the keyslots of a safe file")]
+    List { input: PathBuf },
+    #[command(about = "Add a keyslot unlocked by a new set of key shares")]
+    AddShares {
+        input: PathBuf,
+        #[arg(value_name = "SHARES", help = "Share files of an existing keyslot")]
+        unlock_shares: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "unlock_shares",
+            help = "Unlock with the password of an existing keyslot"
+        )]
+        password: bool,
+        #[arg(
+            short,
+            long = "identity",
+            value_name = "FILE",
+            conflicts_with = "password",
+            help = "Identity file to open sealed shares with (repeatable)"
+        )]
+        identities: Vec<PathBuf>,
+        #[arg(short, long, default_value_t = 5)]
+        shares: usize,
+        #[arg(short, long, default_value_t = 3)]
+        threshold: usize,
+        #[arg(
+            short,
+            long,
+            required_unless_present = "embed",
+            help = "Directory for the new share files"
+        )]
+        outdir: Option<PathBuf>,
+        #[arg(
+            short,
+            long = "recipient",
+            value_name = "PUBKEY",
+            conflicts_with = "shares",
+            help = "Seal one share to this public key or .pub file; repeat once per holder"
+        )]
+        recipients: Vec<String>,
+        #[arg(
+            short,
+            long,
+            requires = "recipients",
+            conflicts_with = "outdir",
+            help = "Store the sealed shares inside the safe file instead of writing share files"
+        )]
+        embed: bool,
+    },
+    #[command(
+        about = "Add a password keyslot (new password read from SAFEFILE_NEW_PASSWORD or prompted)"
+    )]
+    AddPassword {
+        input: PathBuf,
+        #[arg(value_name = "SHARES", help = "Share files of an existing keyslot")]
+        unlock_shares: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "unlock_shares",
+            help = "Unlock with the password of an existing keyslot"
+        )]
+        password: bool,
+        #[arg(
+            short,
+            long = "identity",
+            value_name = "FILE",
+            conflicts_with = "password",
+            help = "Identity file to open sealed shares with (repeatable)"
+        )]
+        identities: Vec<PathBuf>,
+    },
+    #[command(about = "Remove a keyslot")]
+    Remove {
+        input: PathBuf,
+        #[arg(help = "Keyslot ID or a unique prefix of it")]
+        slot: String,
+        #[arg(value_name = "SHARES", help = "Share files of an existing keyslot")]
+        unlock_shares: Vec<PathBuf>,
+        #[arg(
+            short,
+            long,
+            conflicts_with = "unlock_shares",
+            help = "Unlock with the password of an existing keyslot"
+        )]
+        password: bool,
+        #[arg(
+            short,
+            long = "identity",
+            value_name = "FILE",
+            conflicts_with = "password",
+            help = "Identity file to open sealed shares with (repeatable)"
+        )]
+        identities: Vec<PathBuf>,
+    },
 }
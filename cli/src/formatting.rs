@@ -1,7 +1,19 @@
 #[macro_export]
 macro_rules! table_row {
     ($label:expr, $value:expr) => {
-        println!("{:10} {}", $label.yellow(), $value);
+        println!("{:12} {}", $label.yellow(), $value);
+    };
+}
+
+/// Print a status line to stdout, or to stderr if stdout carries data.
+#[macro_export]
+macro_rules! status {
+    ($stderr:expr, $($arg:tt)*) => {
+        if $stderr {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
     };
 }
 
@@ -3,14 +3,26 @@ mod formatting;
 
 use chrono::{TimeZone, Utc};
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, KeyslotCommands, ShareCommands};
 use colored::Colorize;
+use zeroize::Zeroize;
 
 use std::{
+    fs::OpenOptions,
+    io::{BufRead, IsTerminal, Write},
     path::{Path, PathBuf},
 };
 
-use safefile::{error::Error, format, ops, utils};
+use safefile::{
+    archive::{EntryKind, IndexEntry},
+    container::{Compression, Keyslot, KeyslotKind, RecipientKind, SealedShare},
+    crypto::CryptoOptions,
+    error::Error,
+    format::{self, SafeInfo},
+    mnemonic, ops, qr,
+    recipient::{self, Identity, Recipient},
+    shamir, utils,
+};
 
 fn main() {
     let res = run();
@@ -31,6 +43,12 @@ fn run() -> Result<(), Error> {
             threshold,
             outdir,
             label,
+            password,
+            recipients,
+            embed,
+            threads,
+            cipher,
+            compress,
         } => {
             println!(
                 "{} {} -> {}",
@@ -39,37 +57,78 @@ fn run() -> Result<(), Error> {
                 output.display()
             );
 
-            let shares_u8 = u8::try_from(shares).map_err(|_| Error::InvalidArgument {
-                details: format!("shares must be in range [1;255] (got {})", shares),
-            })?;
-            let threshold_u8 = u8::try_from(threshold).map_err(|_| Error::InvalidArgument {
-                details: format!("threshold must be in range [1;255] (got {})", threshold),
-            })?;
+            let time_start = std::time::Instant::now();
 
-            let outdir_path: PathBuf = match outdir {
-                Some(p) => p,
-                None => output
-                    .parent()
-                    .map(|p| p.to_path_buf())
-                    .unwrap_or_else(|| PathBuf::from(".")),
+            let pb = indicatif::ProgressBar::no_length().with_style(progress_style!());
+            let progress = |processed, total| {
+                // Streamed input has no known total.
+                if total > 0 {
+                    pb.set_length(total);
+                }
+                pb.set_position(processed);
             };
 
-            let time_start = std::time::Instant::now();
+            let options = CryptoOptions {
+                threads,
+                cipher,
+                compression_level: compress,
+            };
+            let mut stdin = std::io::stdin().lock();
+            let source = if input == Path::new("-") {
+                ops::Input::Stream(&mut stdin)
+            } else if input.is_dir() {
+                ops::Input::Directory(&input)
+            } else {
+                ops::Input::File(&input)
+            };
 
-            let pb = indicatif::ProgressBar::no_length().with_style(progress_style!());
+            let (result, outdir_path) = if password {
+                let pass = read_password("SAFEFILE_PASSWORD", "Password: ", true)?;
+                let result = ops::encrypt_with_password(
+                    source,
+                    &output,
+                    &pass,
+                    label.as_deref(),
+                    &options,
+                    progress,
+                )?;
+                (result, None)
+            } else {
+                let recipients = load_recipients(&recipients)?;
+                let shares = if recipients.is_empty() {
+                    shares
+                } else {
+                    recipients.len()
+                };
+                let (shares_u8, threshold_u8) = split_params(shares, threshold)?;
 
-            let result = ops::encrypt_and_split(
-                &input,
-                &output,
-                &outdir_path,
-                shares_u8,
-                threshold_u8,
-                label.as_deref(),
-                |processed, total| {
-                    pb.set_length(total);
-                    pb.set_position(processed);
-                },
-            )?;
+                let outdir_path: Option<PathBuf> = match outdir {
+                    Some(p) => Some(p),
+                    None if embed => None,
+                    None => Some(
+                        output
+                            .parent()
+                            .map(|p| p.to_path_buf())
+                            .unwrap_or_else(|| PathBuf::from(".")),
+                    ),
+                };
+
+                let split = ops::ShareOptions {
+                    outdir: outdir_path.as_deref(),
+                    shares: shares_u8,
+                    threshold: threshold_u8,
+                    recipients: &recipients,
+                };
+                let result = ops::encrypt_and_split(
+                    source,
+                    &output,
+                    &split,
+                    label.as_deref(),
+                    &options,
+                    progress,
+                )?;
+                (result, outdir_path)
+            };
 
             pb.finish_and_clear();
 
@@ -82,66 +141,231 @@ fn run() -> Result<(), Error> {
                 .unwrap_or_else(|| result.info.timestamp.to_string());
 
             table_row!("Version:", result.info.version);
-            table_row!("All/Min: ", format!("{} / {}", shares, threshold));
+            table_row!("Cipher:", result.info.cipher);
+            for slot in &result.info.keyslots {
+                table_row!("Unlock:", describe_keyslot(slot));
+            }
             table_row!("Timestamp:", when);
             if !result.info.label.is_empty() {
                 table_row!("Label:", result.info.label);
             }
-            table_row!(
-                "Size:",
-                utils::bytes_to_human_readable(result.info.ciphertext_len)
-            );
+            let size = result.info.original_len.unwrap_or(pb.position());
+            table_row!("Size:", utils::bytes_to_human_readable(size));
+            if let Some(line) = describe_compression(&result.info) {
+                table_row!("Compressed:", line);
+            }
             table_row!("Duration:", utils::us_to_human_readable(time_elapsed));
             table_row!(
                 "Avg speed:",
                 utils::bytes_to_human_readable(
-                    (result.info.ciphertext_len as u128 * 1_000_000 / time_elapsed) as u64
+                    (size as u128 * 1_000_000 / time_elapsed.max(1)) as u64
                 ) + "/s"
             );
-            println!(
-                "{} {} share files to '{}'",
-                "Wrote".green(),
-                result.share_files.len(),
-                outdir_path.display()
-            );
+            if let Some(outdir_path) = outdir_path {
+                println!(
+                    "{} {} share files to '{}'",
+                    "Wrote".green(),
+                    result.share_files.len(),
+                    outdir_path.display()
+                );
+            }
         }
         Commands::Decrypt {
             input,
             output,
             shares,
+            password,
+            identities,
+            mnemonic,
+            threads,
+            range,
         } => {
-            println!(
+            // Status goes to stderr when the plaintext goes to stdout.
+            let to_stdout = output == Path::new("-");
+            status!(
+                to_stdout,
                 "{} {} -> {}",
                 "Decrypting:".green().bold(),
                 input.display(),
                 output.display()
             );
 
+            let mnemonics = if mnemonic {
+                read_mnemonics()?
+            } else {
+                Vec::new()
+            };
+
             let share_paths: Vec<&Path> = shares.iter().map(|p| p.as_path()).collect();
             let time_start = std::time::Instant::now();
 
             let pb = indicatif::ProgressBar::no_length().with_style(progress_style!());
 
-            let written =
-                ops::decrypt_and_reconstruct(&input, &output, &share_paths, |processed, total| {
-                    pb.set_length(total);
-                    pb.set_position(processed);
-                })?;
+            let progress = |processed, total| {
+                pb.set_length(total);
+                pb.set_position(processed);
+            };
+
+            let options = CryptoOptions {
+                threads,
+                ..Default::default()
+            };
+            let mut stdout = std::io::stdout().lock();
+            let sink = if to_stdout {
+                ops::Output::Stream(&mut stdout)
+            } else {
+                ops::Output::File(&output)
+            };
+
+            let (recovered, size) = if let Some(range) = range {
+                let decrypt_range = |unlock: &ops::Unlock| {
+                    ops::decrypt_range(&input, unlock, range.start, range.len, sink, progress)
+                };
+                let written = if password {
+                    let pass = read_password("SAFEFILE_PASSWORD", "Password: ", false)?;
+                    decrypt_range(&ops::Unlock::Password(&pass))?
+                } else if mnemonic {
+                    let texts: Vec<&str> = mnemonics.iter().map(|m| m.as_str()).collect();
+                    decrypt_range(&ops::Unlock::Mnemonics(&texts))?
+                } else {
+                    let identities = load_identities(&identities)?;
+                    decrypt_range(&ops::Unlock::Shares {
+                        paths: &share_paths,
+                        identities: &identities,
+                    })?
+                };
+                (output.display().to_string(), written)
+            } else {
+                let written = if password {
+                    let pass = read_password("SAFEFILE_PASSWORD", "Password: ", false)?;
+                    ops::decrypt_with_password(&input, sink, &pass, &options, progress)?
+                } else if mnemonic {
+                    let texts: Vec<&str> = mnemonics.iter().map(|m| m.as_str()).collect();
+                    ops::decrypt_with_mnemonics(&input, sink, &texts, &options, progress)?
+                } else {
+                    let identities = load_identities(&identities)?;
+                    ops::decrypt_and_reconstruct(
+                        &input,
+                        sink,
+                        &share_paths,
+                        &identities,
+                        &options,
+                        progress,
+                    )?
+                };
+                let size = written.info.original_len.unwrap_or(pb.position());
+                (written.output_file, size)
+            };
 
             pb.finish_and_clear();
 
             let time_elapsed = time_start.elapsed().as_micros();
-            println!("{} {}", "Recovered:".green(), written.output_file);
-            table_row!(
-                "Size:",
-                utils::bytes_to_human_readable(written.info.ciphertext_len)
+            status!(to_stdout, "{} {}", "Recovered:".green(), recovered);
+            status!(
+                to_stdout,
+                "{:10} {}",
+                "Size:".yellow(),
+                utils::bytes_to_human_readable(size)
             );
-            table_row!("Duration:", utils::us_to_human_readable(time_elapsed));
-            table_row!(
-                "Avg speed:",
+            status!(
+                to_stdout,
+                "{:10} {}",
+                "Duration:".yellow(),
+                utils::us_to_human_readable(time_elapsed)
+            );
+            status!(
+                to_stdout,
+                "{:10} {}/s",
+                "Avg speed:".yellow(),
                 utils::bytes_to_human_readable(
-                    (written.info.ciphertext_len as u128 * 1_000_000 / time_elapsed) as u64
-                ) + "/s"
+                    (size as u128 * 1_000_000 / time_elapsed.max(1)) as u64
+                )
+            );
+        }
+        Commands::List {
+            input,
+            shares,
+            password,
+            identities,
+        } => {
+            let entries = with_unlock(password, &shares, &identities, |unlock| {
+                ops::list_archive(&input, unlock, &CryptoOptions::default())
+            })?;
+
+            for entry in &entries {
+                let when = Utc
+                    .timestamp_opt(entry.mtime as i64, 0)
+                    .single()
+                    .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_else(|| entry.mtime.to_string());
+                let mut line = format!(
+                    "{} {:>10} {} {}",
+                    describe_mode(entry),
+                    utils::bytes_to_human_readable(entry.size),
+                    when,
+                    entry.path
+                );
+                if let Some(link) = &entry.link {
+                    line.push_str(&format!(" -> {}", link));
+                }
+                println!("{}", line);
+            }
+        }
+        Commands::Extract {
+            input,
+            path,
+            shares,
+            output,
+            password,
+            identities,
+            threads,
+        } => {
+            let output = match output {
+                Some(output) => output,
+                None => Path::new(&path)
+                    .file_name()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| Error::InvalidArgument {
+                        details: format!("'{}' does not name a file, pass --output", path),
+                    })?,
+            };
+            let to_stdout = output == Path::new("-");
+            status!(
+                to_stdout,
+                "{} {}:{} -> {}",
+                "Extracting:".green().bold(),
+                input.display(),
+                path,
+                output.display()
+            );
+
+            let pb = indicatif::ProgressBar::no_length().with_style(progress_style!());
+            let progress = |processed, total| {
+                pb.set_length(total);
+                pb.set_position(processed);
+            };
+
+            let options = CryptoOptions {
+                threads,
+                ..Default::default()
+            };
+            let mut stdout = std::io::stdout().lock();
+            let sink = if to_stdout {
+                ops::Output::Stream(&mut stdout)
+            } else {
+                ops::Output::File(&output)
+            };
+
+            let entry = with_unlock(password, &shares, &identities, |unlock| {
+                ops::extract_entry(&input, unlock, &path, sink, &options, progress)
+            })?;
+            pb.finish_and_clear();
+
+            status!(
+                to_stdout,
+                "{:10} {}",
+                "Size:".yellow(),
+                utils::bytes_to_human_readable(entry.size)
             );
         }
         Commands::Info { input } => {
@@ -154,14 +378,414 @@ fn run() -> Result<(), Error> {
                 .unwrap_or_else(|| info.timestamp.to_string());
             println!("{}", "Info:".green().bold());
             table_row!("Version:", info.version);
+            table_row!("Cipher:", info.cipher);
+            table_row!("Content:", info.content);
             table_row!("Timestamp:", when);
             if !info.label.is_empty() {
                 table_row!("Label:", info.label);
             }
+            if let Some(file_id) = info.file_id {
+                table_row!("File ID:", hex::encode(file_id));
+            }
+            if info.keyslots.is_empty() {
+                table_row!("Unlock:", "Key shares");
+            }
+            for slot in &info.keyslots {
+                table_row!("Keyslot:", format!("{} {}", hex::encode(slot.id), describe_keyslot(slot)));
+                if let KeyslotKind::Embedded { shares, .. } = &slot.kind {
+                    for sealed in shares {
+                        table_row!("Recipient:", describe_recipient(sealed));
+                    }
+                }
+            }
+
+            if let Some(len) = info.original_len {
+                table_row!("Size:", utils::bytes_to_human_readable(len));
+            }
+            if let Some(line) = describe_compression(&info) {
+                table_row!("Compressed:", line);
+            }
+        }
+        Commands::Keygen { output, kind } => {
+            let identity = Identity::generate(kind);
+
+            // Claim the public key file first, so that neither file is
+            // written if it already exists.
+            let mut pub_path = output.clone().into_os_string();
+            pub_path.push(".pub");
+            let pub_path = PathBuf::from(pub_path);
+            let pub_io_error = |e| Error::Io {
+                path: Some(pub_path.clone()),
+                source: e,
+            };
+            let mut pub_file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&pub_path)
+                .map_err(pub_io_error)?;
+
+            let res = identity.write_to_path(&output).and_then(|_| {
+                writeln!(pub_file, "{}", identity.recipient())
+                    .and_then(|_| pub_file.sync_all())
+                    .map_err(|e| {
+                        let _ = std::fs::remove_file(&output);
+                        pub_io_error(e)
+                    })
+            });
+            if res.is_err() {
+                let _ = std::fs::remove_file(&pub_path);
+            }
+            res?;
+
+            println!("{} {}", "Wrote identity:".green().bold(), output.display());
+            table_row!("Kind:", kind);
+            // Hybrid public keys run to thousands of characters.
+            if kind == RecipientKind::X25519 {
+                table_row!("Public key:", identity.recipient());
+            }
+            table_row!("Fingerprint:", identity.recipient().fingerprint());
+            table_row!("Public file:", pub_path.display());
+        }
+        Commands::Keyslot(cmd) => run_keyslot(cmd)?,
+        Commands::Armor { input, output } => {
+            ops::armor_safe(&input, &output)?;
+            println!("{} {} -> {}", "Armored:".green().bold(), input.display(), output.display());
+        }
+        Commands::Dearmor { input, output } => {
+            ops::dearmor_safe(&input, &output)?;
+            println!(
+                "{} {} -> {}",
+                "Dearmored:".green().bold(),
+                input.display(),
+                output.display()
+            );
+        }
+        Commands::Share(ShareCommands::Export { share, armor: true, .. }) => {
+            print!("{}", shamir::armor_share(&share)?);
+        }
+        Commands::Share(ShareCommands::Export {
+            share, identities, ..
+        }) => {
+            let identities = load_identities(&identities)?;
+            let mut sfile = shamir::read_share(&share, &identities)?;
+            let words = mnemonic::encode(&sfile);
+            sfile.share.zeroize();
+
+            // Numbered words, six per line; the numbers are skipped when
+            // the mnemonic is read back.
+            for (line, chunk) in words?.chunks(6).enumerate() {
+                let row: Vec<String> = chunk
+                    .iter()
+                    .enumerate()
+                    .map(|(i, w)| format!("{:>3}. {:<8}", line * 6 + i + 1, w))
+                    .collect();
+                println!("{}", row.join(" ").trim_end());
+            }
+        }
+        Commands::Share(ShareCommands::Qr {
+            share,
+            output: Some(output),
+        }) => {
+            ops::share_qr(&share, &output)?;
+            println!(
+                "{} {} -> {}",
+                "Wrote QR code:".green().bold(),
+                share.display(),
+                output.display()
+            );
+        }
+        Commands::Share(ShareCommands::Qr { share, output: None }) => {
+            let export = shamir::export_share(&share)?;
+            print!("{}", qr::ShareQr::new(&export, &share)?.to_terminal());
+        }
+        Commands::Share(ShareCommands::Sheet {
+            shares,
+            outdir,
+            holders,
+            identities,
+        }) => {
+            let identities = load_identities(&identities)?;
+            let shares: Vec<&Path> = shares.iter().map(PathBuf::as_path).collect();
+            let holders: Vec<&str> = holders.iter().map(String::as_str).collect();
+            let written = ops::write_share_sheets(&shares, &holders, &identities, &outdir)?;
 
-            table_row!("Size:", utils::bytes_to_human_readable(info.ciphertext_len));
+            println!(
+                "{} {} share sheets to '{}'",
+                "Wrote".green(),
+                written.len(),
+                outdir.display()
+            );
+            println!("Print them from a browser; each sheet holds a secret share.");
         }
     }
 
     Ok(())
 }
+
+fn run_keyslot(cmd: KeyslotCommands) -> Result<(), Error> {
+    match cmd {
+        KeyslotCommands::List { input } => {
+            println!("{} {}", "Keyslots:".green().bold(), input.display());
+            let info = format::inspect_safe_from_path(&input)?;
+            if info.keyslots.is_empty() {
+                println!("Legacy safefile, its key shares split the data key directly");
+            }
+            for slot in &info.keyslots {
+                table_row!(hex::encode(slot.id).as_str(), describe_keyslot(slot));
+                if let KeyslotKind::Embedded { shares, .. } = &slot.kind {
+                    for sealed in shares {
+                        table_row!("Recipient:", describe_recipient(sealed));
+                    }
+                }
+            }
+        }
+        KeyslotCommands::AddShares {
+            input,
+            unlock_shares,
+            password,
+            identities,
+            shares,
+            threshold,
+            outdir,
+            recipients,
+            embed: _,
+        } => {
+            let recipients = load_recipients(&recipients)?;
+            let shares = if recipients.is_empty() {
+                shares
+            } else {
+                recipients.len()
+            };
+            let (shares_u8, threshold_u8) = split_params(shares, threshold)?;
+            let new = ops::NewKeyslot::Shares(ops::ShareOptions {
+                outdir: outdir.as_deref(),
+                shares: shares_u8,
+                threshold: threshold_u8,
+                recipients: &recipients,
+            });
+            let result = with_unlock(password, &unlock_shares, &identities, |unlock| {
+                ops::add_keyslot(&input, unlock, &new)
+            })?;
+
+            println!("{} {}", "Added keyslot:".green(), hex::encode(result.slot_id));
+            table_row!("All/Min: ", format!("{} / {}", shares, threshold));
+            match outdir {
+                Some(outdir) => println!(
+                    "{} {} share files to '{}'",
+                    "Wrote".green(),
+                    result.share_files.len(),
+                    outdir.display()
+                ),
+                None => println!("{} {} sealed shares", "Embedded".green(), shares),
+            }
+        }
+        KeyslotCommands::AddPassword {
+            input,
+            unlock_shares,
+            password,
+            identities,
+        } => {
+            let result = with_unlock(password, &unlock_shares, &identities, |unlock| {
+                let new_pass = read_password("SAFEFILE_NEW_PASSWORD", "New password: ", true)?;
+                ops::add_keyslot(&input, unlock, &ops::NewKeyslot::Password(&new_pass))
+            })?;
+
+            println!("{} {}", "Added keyslot:".green(), hex::encode(result.slot_id));
+        }
+        KeyslotCommands::Remove {
+            input,
+            slot,
+            unlock_shares,
+            password,
+            identities,
+        } => {
+            let info = format::inspect_safe_from_path(&input)?;
+            let matches: Vec<[u8; 8]> = info
+                .keyslots
+                .iter()
+                .map(|s| s.id)
+                .filter(|id| hex::encode(id).starts_with(&slot.to_lowercase()))
+                .collect();
+            let slot_id = match matches.as_slice() {
+                [id] => *id,
+                [] => return Err(Error::KeyslotNotFound { id: slot }),
+                _ => {
+                    return Err(Error::InvalidArgument {
+                        details: format!("keyslot prefix '{}' is ambiguous", slot),
+                    });
+                }
+            };
+
+            with_unlock(password, &unlock_shares, &identities, |unlock| {
+                ops::remove_keyslot(&input, unlock, &slot_id)
+            })?;
+
+            println!("{} {}", "Removed keyslot:".green(), hex::encode(slot_id));
+        }
+    }
+
+    Ok(())
+}
+
+/// Validate share and threshold counts given on the command line.
+fn split_params(shares: usize, threshold: usize) -> Result<(u8, u8), Error> {
+    let shares_u8 = u8::try_from(shares).map_err(|_| Error::InvalidArgument {
+        details: format!("shares must be in range [1;255] (got {})", shares),
+    })?;
+    let threshold_u8 = u8::try_from(threshold).map_err(|_| Error::InvalidArgument {
+        details: format!("threshold must be in range [1;255] (got {})", threshold),
+    })?;
+    Ok((shares_u8, threshold_u8))
+}
+
+/// Run `f` with the unlock method given by `--password` or share files.
+fn with_unlock<T, F>(
+    password: bool,
+    shares: &[PathBuf],
+    identities: &[PathBuf],
+    f: F,
+) -> Result<T, Error>
+where
+    F: FnOnce(&ops::Unlock) -> Result<T, Error>,
+{
+    if password {
+        let pass = read_password("SAFEFILE_PASSWORD", "Password: ", false)?;
+        f(&ops::Unlock::Password(&pass))
+    } else {
+        let share_paths: Vec<&Path> = shares.iter().map(|p| p.as_path()).collect();
+        let identities = load_identities(identities)?;
+        f(&ops::Unlock::Shares {
+            paths: &share_paths,
+            identities: &identities,
+        })
+    }
+}
+
+/// Parse `--recipient` values, each a public key or a path to a `.pub` file.
+fn load_recipients(values: &[String]) -> Result<Vec<Recipient>, Error> {
+    values
+        .iter()
+        .map(|v| {
+            let path = Path::new(v);
+            if path.is_file() {
+                let text = std::fs::read_to_string(path).map_err(|e| Error::Io {
+                    path: Some(path.to_path_buf()),
+                    source: e,
+                })?;
+                text.parse()
+            } else {
+                v.parse()
+            }
+        })
+        .collect()
+}
+
+fn load_identities(paths: &[PathBuf]) -> Result<Vec<Identity>, Error> {
+    paths.iter().map(|p| Identity::read_from_path(p)).collect()
+}
+
+/// Read share mnemonics from stdin, separated by empty lines.
+fn read_mnemonics() -> Result<Vec<String>, Error> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        eprintln!("Enter share mnemonics, separated by an empty line; finish with Ctrl-D:");
+    }
+
+    let mut mnemonics = vec![String::new()];
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let current = mnemonics.last_mut().expect("never empty");
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                mnemonics.push(String::new());
+            }
+            continue;
+        }
+        current.push_str(&line);
+        current.push('\n');
+    }
+    mnemonics.retain(|m| !m.is_empty());
+    Ok(mnemonics)
+}
+
+/// Read a password from the environment variable `env` or prompt for it on
+/// the terminal.
+fn read_password(env: &str, prompt: &str, confirm: bool) -> Result<String, Error> {
+    if let Ok(pass) = std::env::var(env) {
+        if pass.is_empty() {
+            return Err(Error::InvalidArgument {
+                details: format!("password in {} must not be empty", env),
+            });
+        }
+        return Ok(pass);
+    }
+
+    let pass = rpassword::prompt_password(prompt)?;
+    if pass.is_empty() {
+        return Err(Error::InvalidArgument {
+            details: "password must not be empty".into(),
+        });
+    }
+    if confirm && rpassword::prompt_password("Repeat password: ")? != pass {
+        return Err(Error::InvalidArgument {
+            details: "passwords do not match".into(),
+        });
+    }
+    Ok(pass)
+}
+
+/// Compressed payload size and ratio, for files that were compressed.
+fn describe_compression(info: &SafeInfo) -> Option<String> {
+    if info.compression == Compression::None {
+        return None;
+    }
+    let compressed = info.ciphertext_len?;
+    let mut line = format!(
+        "{} ({})",
+        utils::bytes_to_human_readable(compressed),
+        info.compression
+    );
+    if let Some(original) = info.original_len.filter(|&len| len > 0) {
+        line.push_str(&format!(", {:.1}% of original", compressed as f64 * 100.0 / original as f64));
+    }
+    Some(line)
+}
+
+/// `ls -l` style type and permissions of an archive entry.
+fn describe_mode(entry: &IndexEntry) -> String {
+    let kind = match entry.kind {
+        EntryKind::File => '-',
+        EntryKind::Directory => 'd',
+        EntryKind::Symlink => 'l',
+    };
+    let bits: String = (0..9)
+        .map(|i| {
+            if entry.mode & (0o400 >> i) == 0 {
+                '-'
+            } else {
+                ['r', 'w', 'x'][i % 3]
+            }
+        })
+        .collect();
+    format!("{}{}", kind, bits)
+}
+
+/// Kind and fingerprint of the key a share is sealed to.
+fn describe_recipient(sealed: &SealedShare) -> String {
+    format!("{} {}", sealed.kind, recipient::fingerprint(sealed.kind, &sealed.recipient))
+}
+
+fn describe_keyslot(keyslot: &Keyslot) -> String {
+    match &keyslot.kind {
+        KeyslotKind::Shamir { threshold, shares } => {
+            format!("Key shares ({} of {})", threshold, shares)
+        }
+        KeyslotKind::Password { kdf, .. } => format!(
+            "Password (Argon2id, m={} KiB, t={}, p={})",
+            kdf.memory_kib, kdf.iterations, kdf.parallelism
+        ),
+        KeyslotKind::Embedded { threshold, shares } => {
+            format!("Embedded key shares ({} of {})", threshold, shares.len())
+        }
+    }
+}
@@ -0,0 +1,418 @@
+//! Directory trees stored as the payload of a safefile.
+//!
+//! A tree is written as a tar stream, which records the path, permissions
+//! and modification time of every entry and the target of every symlink.
+//! Unpacking only ever writes inside the target directory.
+//!
+//! The tar stream is followed by an index of its entries, then the index
+//! length and `consts::INDEX_MAGIC`. Since the index ends the plaintext, a
+//! reader with random access finds it, and the data of any file, without
+//! decrypting the rest.
+
+use crate::consts;
+use crate::error::Error;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Builder, Entry, EntryType, Header, HeaderMode};
+use walkdir::WalkDir;
+
+/// Entry of an archive.
+///
+/// - `path` is relative to the archived directory, with `/` separators.
+/// - `size` is the length of a file's data, 0 for other entries.
+/// - `mode` holds the Unix permission bits and `mtime` is in UNIX seconds.
+/// - `offset` is where a file's data starts in the plaintext.
+/// - `link` is the target of a symlink.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: u64,
+    pub offset: u64,
+    pub link: Option<String>,
+}
+
+/// Kind of an archive entry.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl IndexEntry {
+    /// Whether the entry is at `path`, ignoring `.` components and a
+    /// trailing separator.
+    pub fn is_at(&self, path: &str) -> bool {
+        Path::new(&self.path)
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .eq(Path::new(path).components().filter(|c| *c != Component::CurDir))
+    }
+}
+
+/// Write the tree under `dir` to `writer` as a tar stream followed by its
+/// index, and return `writer`.
+///
+/// Entries are written in name order with paths relative to `dir`; symlinks
+/// are stored, not followed. Hard links are stored as separate files, and
+/// other special files such as sockets and devices are rejected.
+pub fn write_tree<W: Write>(dir: &Path, writer: W) -> Result<W, Error> {
+    let mut builder = Builder::new(Counting {
+        inner: writer,
+        written: 0,
+    });
+    let mut index = Vec::new();
+
+    for entry in WalkDir::new(dir)
+        .follow_links(false)
+        .min_depth(1)
+        .sort_by_file_name()
+    {
+        let entry = entry.map_err(|e| walk_error(dir, e))?;
+        let path = entry.path();
+        let name = path
+            .strip_prefix(dir)
+            .expect("walked paths are under the root");
+        let meta = entry.metadata().map_err(|e| walk_error(dir, e))?;
+
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&meta, HeaderMode::Complete);
+        let mut indexed = IndexEntry {
+            path: entry_path(name),
+            kind: EntryKind::File,
+            size: 0,
+            mode: header.mode()?,
+            mtime: header.mtime()?,
+            offset: 0,
+            link: None,
+        };
+
+        let kind = meta.file_type();
+        if kind.is_dir() {
+            indexed.kind = EntryKind::Directory;
+            builder.append_data(&mut header, name, io::empty())?;
+        } else if kind.is_symlink() {
+            let target = fs::read_link(path).map_err(|e| io_error(path, e))?;
+            indexed.kind = EntryKind::Symlink;
+            indexed.link = Some(target.to_string_lossy().into_owned());
+            builder.append_link(&mut header, name, target)?;
+        } else if kind.is_file() {
+            let file = File::open(path).map_err(|e| io_error(path, e))?;
+            let exact = Exact {
+                file,
+                remaining: meta.len(),
+                path: path.to_path_buf(),
+            };
+            builder.append_data(&mut header, name, exact)?;
+            // The data ends the entry, padded to a whole tar block.
+            indexed.size = meta.len();
+            indexed.offset = builder.get_ref().written - meta.len().next_multiple_of(512);
+        } else {
+            return Err(Error::InvalidArgument {
+                details: format!(
+                    "'{}' is not a regular file, directory or symlink",
+                    path.display()
+                ),
+            });
+        }
+        index.push(indexed);
+    }
+
+    let mut writer = builder.into_inner()?.inner;
+    let encoded = bincode::serde::encode_to_vec(&index, bincode::config::standard())?;
+    writer.write_all(&encoded)?;
+    writer.write_all(&(encoded.len() as u64).to_le_bytes())?;
+    writer.write_all(consts::INDEX_MAGIC)?;
+
+    Ok(writer)
+}
+
+/// Give the file extracted from `entry` at `path` the permissions and
+/// modification time recorded for it.
+pub fn restore_metadata(path: &Path, entry: &IndexEntry) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(entry.mode & 0o777))
+            .map_err(|e| io_error(path, e))?;
+    }
+    filetime::set_file_mtime(path, FileTime::from_unix_time(entry.mtime as i64, 0))
+        .map_err(|e| io_error(path, e))
+}
+
+/// Length of the index an archive ends with, from the last
+/// `consts::INDEX_FOOTER_LEN` bytes of its plaintext.
+pub fn index_len(footer: &[u8], path: &Path) -> Result<u64, Error> {
+    match footer.strip_suffix(consts::INDEX_MAGIC.as_slice()) {
+        Some(len) if len.len() == 8 => Ok(u64::from_le_bytes(len.try_into().expect("checked length"))),
+        _ => Err(Error::InvalidFormat {
+            path: path.to_path_buf(),
+            details: "missing archive index".into(),
+        }),
+    }
+}
+
+/// Decode the index of an archive.
+pub fn decode_index(raw: &[u8], path: &Path) -> Result<Vec<IndexEntry>, Error> {
+    let (index, _) = bincode::serde::decode_from_slice(raw, bincode::config::standard())
+        .map_err(|e| Error::InvalidFormat {
+            path: path.to_path_buf(),
+            details: format!("invalid archive index: {}", e),
+        })?;
+    Ok(index)
+}
+
+/// Read the tar stream from `reader` in order, calling `f` with each entry
+/// and, for files, a reader of its data; return `reader` positioned after
+/// the archive.
+///
+/// This is how archives are listed when the index cannot be reached
+/// without reading everything before it, as with compressed payloads.
+/// `path` names the safefile in errors.
+pub fn scan_tree<R, F>(reader: R, path: &Path, mut f: F) -> Result<R, Error>
+where
+    R: Read,
+    F: FnMut(&IndexEntry, &mut dyn Read) -> Result<(), Error>,
+{
+    let mut archive = Archive::new(Stash {
+        inner: reader,
+        error: None,
+    });
+
+    let res = scan_entries(&mut archive, path, &mut f);
+    let stash = archive.into_inner();
+    if let Some(e) = stash.error {
+        return Err(e);
+    }
+    res?;
+
+    Ok(stash.inner)
+}
+
+fn scan_entries<R, F>(archive: &mut Archive<R>, path: &Path, f: &mut F) -> Result<(), Error>
+where
+    R: Read,
+    F: FnMut(&IndexEntry, &mut dyn Read) -> Result<(), Error>,
+{
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let indexed = scanned_entry(&entry, path)?;
+        f(&indexed, &mut entry)?;
+    }
+    Ok(())
+}
+
+/// Index entry of a tar entry read in order.
+fn scanned_entry<R: Read>(entry: &Entry<R>, path: &Path) -> Result<IndexEntry, Error> {
+    let header = entry.header();
+    let name = entry.path()?;
+    let kind = match header.entry_type() {
+        EntryType::Regular => EntryKind::File,
+        EntryType::Directory => EntryKind::Directory,
+        EntryType::Symlink => EntryKind::Symlink,
+        other => {
+            return Err(Error::InvalidFormat {
+                path: path.to_path_buf(),
+                details: format!(
+                    "archive entry '{}' has unsupported type {:?}",
+                    name.display(),
+                    other
+                ),
+            });
+        }
+    };
+
+    Ok(IndexEntry {
+        path: entry_path(&name),
+        kind,
+        size: if kind == EntryKind::File { entry.size() } else { 0 },
+        mode: header.mode()?,
+        mtime: header.mtime()?,
+        offset: entry.raw_file_position(),
+        link: entry
+            .link_name()?
+            .map(|target| target.to_string_lossy().into_owned()),
+    })
+}
+
+/// `name` with `/` separators, as tar stores it.
+fn entry_path(name: &Path) -> String {
+    name.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Unpack the tar stream read from `reader` into the existing directory
+/// `dest`, and return `reader` positioned after the archive.
+///
+/// `path` names the safefile in errors. Entries with absolute paths or `..`
+/// components are rejected, as are entries reached through a symlink that
+/// leaves `dest`. Directories are restored last, so they keep the
+/// permissions and modification times recorded for them.
+pub fn unpack_tree<R: Read>(reader: R, dest: &Path, path: &Path) -> Result<R, Error> {
+    let mut archive = Archive::new(Stash {
+        inner: reader,
+        error: None,
+    });
+    archive.set_overwrite(false);
+    archive.set_preserve_mtime(true);
+
+    let res = unpack_entries(&mut archive, dest, path);
+    let stash = archive.into_inner();
+    // An error from the payload is more telling than what tar made of it.
+    if let Some(e) = stash.error {
+        return Err(e);
+    }
+    res?;
+
+    Ok(stash.inner)
+}
+
+fn unpack_entries<R: Read>(archive: &mut Archive<R>, dest: &Path, path: &Path) -> Result<(), Error> {
+    let mut dirs = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        if !is_relative_inside(&name) {
+            return Err(Error::InvalidFormat {
+                path: path.to_path_buf(),
+                details: format!("archive entry '{}' escapes the target directory", name.display()),
+            });
+        }
+
+        match entry.header().entry_type() {
+            EntryType::Directory => dirs.push(entry),
+            EntryType::Regular | EntryType::Symlink => {
+                entry.unpack_in(dest)?;
+            }
+            other => {
+                return Err(Error::InvalidFormat {
+                    path: path.to_path_buf(),
+                    details: format!(
+                        "archive entry '{}' has unsupported type {:?}",
+                        name.display(),
+                        other
+                    ),
+                });
+            }
+        }
+    }
+
+    // Children come after their parents in the archive, so restoring in
+    // reverse sets a read-only parent only once its children are done.
+    // tar leaves directory modification times to the caller.
+    for mut dir in dirs.into_iter().rev() {
+        dir.unpack_in(dest)?;
+        let mtime = FileTime::from_unix_time(dir.header().mtime()? as i64, 0);
+        let target = dest.join(dir.path()?);
+        filetime::set_file_mtime(&target, mtime).map_err(|e| io_error(&target, e))?;
+    }
+    Ok(())
+}
+
+/// Whether `name` is a non-empty relative path without `..` components.
+fn is_relative_inside(name: &Path) -> bool {
+    let mut normal = false;
+    for part in name.components() {
+        match part {
+            Component::Normal(_) => normal = true,
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    normal
+}
+
+/// Reads exactly `remaining` bytes of a file, so a file that changes size
+/// while it is archived cannot disagree with the size in its tar header.
+struct Exact {
+    file: File,
+    remaining: u64,
+    path: PathBuf,
+}
+
+impl Read for Exact {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+
+        let max = buf.len().min(self.remaining as usize);
+        let n = self
+            .file
+            .read(&mut buf[..max])
+            .map_err(|e| io_error(&self.path, e))?;
+        if n == 0 {
+            return Err(Error::InvalidArgument {
+                details: format!("'{}' shrank while it was archived", self.path.display()),
+            }
+            .into());
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Counts the bytes written, giving the offsets of entries.
+struct Counting<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(data)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Keeps the first error of the payload reader, which tar would otherwise
+/// turn into a message about the entry being unpacked.
+struct Stash<R> {
+    inner: R,
+    error: Option<Error>,
+}
+
+impl<R: Read> Read for Stash<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            let e = Error::from(e);
+            let err = io::Error::other(e.to_string());
+            self.error.get_or_insert(e);
+            err
+        })
+    }
+}
+
+fn io_error(path: &Path, source: io::Error) -> Error {
+    Error::Io {
+        path: Some(path.to_path_buf()),
+        source,
+    }
+}
+
+fn walk_error(root: &Path, e: walkdir::Error) -> Error {
+    let path = e.path().unwrap_or(root).to_path_buf();
+    match e.into_io_error() {
+        Some(source) => Error::Io {
+            path: Some(path),
+            source,
+        },
+        None => Error::InvalidArgument {
+            details: format!("'{}' contains a symlink loop", path.display()),
+        },
+    }
+}
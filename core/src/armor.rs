@@ -0,0 +1,160 @@
+//! ASCII armor for share files and small safefiles.
+//!
+//! An armored block looks like this, in the style of OpenPGP (RFC 4880):
+//!
+//! ```text
+//! -----BEGIN SAFEFILE SHARE-----
+//! Label: tax docs
+//! Index: 1 of 5
+//!
+//! <base64 body, 64 columns>
+//! =<base64 CRC-24 of the body>
+//! -----END SAFEFILE SHARE-----
+//! ```
+//!
+//! Headers are informational only; the body is authoritative.
+
+use crate::error::Error;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::path::Path;
+
+/// Maximum size of binary data accepted for armoring (4 MiB).
+pub const MAX_ARMOR_LEN: u64 = 4 * 1024 * 1024;
+
+/// Characters per body line.
+const LINE_LEN: usize = 64;
+/// Common prefix of every armor begin line.
+const BEGIN_PREFIX: &str = "-----BEGIN SAFEFILE";
+
+/// Content of an armored block.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArmorKind {
+    Share,
+    Safefile,
+}
+
+impl ArmorKind {
+    fn name(self) -> &'static str {
+        match self {
+            ArmorKind::Share => "SAFEFILE SHARE",
+            ArmorKind::Safefile => "SAFEFILE",
+        }
+    }
+}
+
+/// Armor `data` as a block of `kind` with the given headers.
+pub fn encode(kind: ArmorKind, headers: &[(&str, String)], data: &[u8]) -> String {
+    let mut out = format!("-----BEGIN {}-----\n", kind.name());
+    for (name, value) in headers {
+        // Header values are single lines.
+        let value = value.replace(['\r', '\n'], " ");
+        out += &format!("{}: {}\n", name, value);
+    }
+    out.push('\n');
+
+    let body = STANDARD.encode(data);
+    for line in body.as_bytes().chunks(LINE_LEN) {
+        out += std::str::from_utf8(line).expect("base64 is ASCII");
+        out.push('\n');
+    }
+
+    let crc = crc24(data).to_be_bytes();
+    out += &format!("={}\n", STANDARD.encode(&crc[1..]));
+    out += &format!("-----END {}-----\n", kind.name());
+    out
+}
+
+/// Whether `raw` contains a line starting with an armor begin line.
+pub fn is_armored(raw: &[u8]) -> bool {
+    raw.split(|&b| b == b'\n')
+        .any(|line| line.trim_ascii_start().starts_with(BEGIN_PREFIX.as_bytes()))
+}
+
+/// Decode an armored block of `kind` read from `path`.
+///
+/// Text before the begin line and after the end line is ignored, so a
+/// block can be pasted together with surrounding text.
+pub fn decode(text: &str, kind: ArmorKind, path: &Path) -> Result<Vec<u8>, Error> {
+    let invalid = |details: &str| Error::InvalidArmor {
+        path: path.to_path_buf(),
+        details: details.to_string(),
+    };
+
+    let begin = format!("-----BEGIN {}-----", kind.name());
+    let end = format!("-----END {}-----", kind.name());
+
+    let mut lines = text
+        .lines()
+        .map(str::trim)
+        .skip_while(|l| !l.starts_with(BEGIN_PREFIX));
+    match lines.next() {
+        Some(l) if l == begin => {}
+        Some(_) => return Err(invalid(&format!("expected a {} block", kind.name()))),
+        None => return Err(invalid("missing begin line")),
+    }
+
+    // Headers run until the first empty line.
+    for line in lines.by_ref() {
+        if line.is_empty() {
+            break;
+        }
+        if !line.contains(": ") {
+            return Err(invalid(&format!("malformed header line '{}'", line)));
+        }
+    }
+
+    let mut body = String::new();
+    let mut crc = None;
+    let mut ended = false;
+    for line in lines {
+        if line == end {
+            ended = true;
+            break;
+        }
+        if let Some(c) = line.strip_prefix('=') {
+            crc = Some(c.to_string());
+        } else if crc.is_some() {
+            return Err(invalid("data after checksum line"));
+        } else {
+            body += line;
+        }
+    }
+    if !ended {
+        return Err(invalid("missing end line"));
+    }
+
+    let data = STANDARD
+        .decode(body)
+        .map_err(|e| invalid(&format!("invalid base64: {}", e)))?;
+
+    let crc = crc.ok_or_else(|| invalid("missing checksum line"))?;
+    let crc = STANDARD
+        .decode(crc)
+        .ok()
+        .filter(|c| c.len() == 3)
+        .ok_or_else(|| invalid("malformed checksum line"))?;
+    if crc[..] != crc24(&data).to_be_bytes()[1..] {
+        return Err(invalid("checksum mismatch, the text was altered or mistyped"));
+    }
+
+    Ok(data)
+}
+
+/// CRC-24 as used by OpenPGP armor (RFC 4880, section 6.1).
+fn crc24(data: &[u8]) -> u32 {
+    const INIT: u32 = 0xB704CE;
+    const POLY: u32 = 0x1864CFB;
+
+    let mut crc = INIT;
+    for &b in data {
+        crc ^= (b as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= POLY;
+            }
+        }
+    }
+    crc & 0xFFFFFF
+}
@@ -0,0 +1,218 @@
+//! Output files and directories that only appear at their final path once
+//! complete.
+
+use crate::error::Error;
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A file staged in a sibling temporary file.
+///
+/// Data is written to `.<name>.<random>.tmp` next to the target path. The
+/// temporary file is fsynced and renamed over the target by `commit`; if the
+/// `AtomicFile` is dropped without being committed, the temporary file is
+/// removed and the target is left untouched.
+pub struct AtomicFile {
+    file: Option<File>,
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+    committed: bool,
+    /// Whether the target is an empty file reserved by `create_new`.
+    reserved: bool,
+}
+
+impl AtomicFile {
+    /// Create a new temporary file for `path`.
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let tmp_path = staging_path(path)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .map_err(|e| Error::Io {
+                path: Some(tmp_path.clone()),
+                source: e,
+            })?;
+
+        Ok(Self {
+            file: Some(file),
+            tmp_path,
+            final_path: path.to_path_buf(),
+            committed: false,
+            reserved: false,
+        })
+    }
+
+    /// Like `create`, but fail if `path` already exists.
+    ///
+    /// The target is reserved as an empty file until the `AtomicFile` is
+    /// committed over it, or removed again if it is dropped.
+    pub fn create_new(path: &Path) -> Result<Self, Error> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::AlreadyExists => Error::InvalidArgument {
+                    details: format!("'{}' already exists", path.display()),
+                },
+                _ => Error::Io {
+                    path: Some(path.to_path_buf()),
+                    source: e,
+                },
+            })?;
+
+        match Self::create(path) {
+            Ok(mut staged) => {
+                staged.reserved = true;
+                Ok(staged)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(path);
+                Err(e)
+            }
+        }
+    }
+
+    /// Path of the temporary file being written.
+    pub fn path(&self) -> &Path {
+        &self.tmp_path
+    }
+
+    /// Flush data to disk and move the file to its final path.
+    pub fn commit(mut self) -> Result<(), Error> {
+        if let Some(file) = self.file.take() {
+            file.sync_all().map_err(|e| Error::Io {
+                path: Some(self.tmp_path.clone()),
+                source: e,
+            })?;
+        }
+
+        fs::rename(&self.tmp_path, &self.final_path).map_err(|e| Error::Io {
+            path: Some(self.final_path.clone()),
+            source: e,
+        })?;
+        self.committed = true;
+
+        // Persist the rename itself; failure here does not undo the commit.
+        sync_parent(&self.final_path);
+
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("file is open until commit").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().expect("file is open until commit").flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        // Close the handle first so removal also works on Windows.
+        self.file.take();
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp_path);
+            if self.reserved {
+                let _ = fs::remove_file(&self.final_path);
+            }
+        }
+    }
+}
+
+/// A directory staged in a sibling temporary directory.
+///
+/// Works like `AtomicFile`, except that the target must not exist yet:
+/// `commit` renames the staged directory into place, and dropping the
+/// `AtomicDir` uncommitted removes the staged tree.
+pub struct AtomicDir {
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicDir {
+    /// Create a new temporary directory for `path`.
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        if fs::symlink_metadata(path).is_ok() {
+            return Err(Error::InvalidArgument {
+                details: format!("'{}' already exists", path.display()),
+            });
+        }
+
+        let tmp_path = staging_path(path)?;
+        fs::create_dir(&tmp_path).map_err(|e| Error::Io {
+            path: Some(tmp_path.clone()),
+            source: e,
+        })?;
+
+        Ok(Self {
+            tmp_path,
+            final_path: path.to_path_buf(),
+            committed: false,
+        })
+    }
+
+    /// Path of the staged directory to fill.
+    pub fn path(&self) -> &Path {
+        &self.tmp_path
+    }
+
+    /// Move the directory to its final path.
+    pub fn commit(mut self) -> Result<(), Error> {
+        fs::rename(&self.tmp_path, &self.final_path).map_err(|e| Error::Io {
+            path: Some(self.final_path.clone()),
+            source: e,
+        })?;
+        self.committed = true;
+
+        sync_parent(&self.final_path);
+        Ok(())
+    }
+}
+
+impl Drop for AtomicDir {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_dir_all(&self.tmp_path);
+        }
+    }
+}
+
+/// Random sibling path `.<name>.<random>.tmp` to stage `path` at.
+fn staging_path(path: &Path) -> Result<PathBuf, Error> {
+    let name = path.file_name().ok_or_else(|| Error::InvalidArgument {
+        details: format!("'{}' is not a file path", path.display()),
+    })?;
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let mut suffix = [0u8; 8];
+    OsRng.fill_bytes(&mut suffix);
+    Ok(dir.join(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        hex::encode(suffix)
+    )))
+}
+
+/// Fsync the directory holding `path`, persisting a rename into it.
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(d) = File::open(dir) {
+            let _ = d.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
@@ -1,3 +1,50 @@
-pub const VERSION: u8 = 1;
+pub const VERSION: u8 = 2;
+/// Oldest format version that can still be decrypted.
+pub const MIN_VERSION: u8 = 1;
+/// Last format version using a single AES-GCM stream instead of chunks.
+pub const LAST_STREAM_VERSION: u8 = 1;
 pub const MAGIC: &[u8; 4] = b"SFIL";
+/// Magic of the trailer following the last chunk.
+pub const TRAILER_MAGIC: &[u8; 4] = b"SFTR";
+/// Size of the trailer: magic, payload length and original length.
+pub const TRAILER_LEN: usize = 20;
+
+/// Domain separation prefix for the key check value.
+pub const KEY_CHECK_CONTEXT: &[u8] = b"safefile key check v1";
+
+/// Current share file format version.
+pub const SHARE_VERSION: u8 = 2;
+pub const SHARE_MAGIC: &[u8; 4] = b"SFSH";
+/// Current sealed share format version.
+pub const SEALED_SHARE_VERSION: u8 = 2;
+pub const SEALED_SHARE_MAGIC: &[u8; 4] = b"SFSE";
+/// Sealed shares of this version carry no recipient kind.
+pub const LEGACY_SEALED_SHARE_VERSION: u8 = 1;
+/// HKDF info string for the key sealing a share to a recipient.
+pub const SEAL_CONTEXT: &[u8] = b"safefile share seal v1";
+/// HKDF info string for the key sealing a share to a hybrid recipient.
+pub const HYBRID_SEAL_CONTEXT: &[u8] = b"safefile share seal mlkem768x25519 v1";
+
+/// Current mnemonic share encoding version, stored as the first word.
+pub const MNEMONIC_VERSION: u8 = 1;
+
+/// Share files of this version carry no file identifier.
+pub const LEGACY_SHARE_VERSION: u8 = 1;
+
+/// Plaintext bytes per chunk in the chunked format.
+pub const CHUNK_SIZE: u32 = 64 * 1024;
+/// Chunks buffered per worker thread, so each batch keeps every worker busy.
+pub const CHUNKS_PER_THREAD: usize = 4;
+
+/// Default zstd level when compression is enabled.
+pub const ZSTD_LEVEL: i32 = 3;
+/// Percentage by which the first chunk must shrink for compression to be
+/// used; most already compressed data shrinks by less.
+pub const MIN_COMPRESSION_SAVING: u64 = 5;
+/// Magic closing the index at the end of an archive payload.
+pub const INDEX_MAGIC: &[u8; 4] = b"SFIX";
+/// Size of the footer after an archive index: index length and magic.
+pub const INDEX_FOOTER_LEN: usize = 12;
+/// Size of the authentication tag appended to every chunk.
+pub const TAG_SIZE: usize = 16;
 
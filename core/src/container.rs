@@ -1,16 +1,207 @@
 //! Container definitions for on-disk structures.
 
+use crate::consts;
+use crate::error::Error;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
 /// Safefile header.
 ///
 /// - `version` identifies the format version.
 /// - `timestamp` is UNIX seconds when the file was created.
 /// - `label` is an optional user label.
-/// - `nonce` is a 12-byte AES nonce used for encryption.
-/// - `ciphertext_len` is the size of the ciphertext in bytes.
-#[derive(Serialize, Deserialize)]
+/// - `file_id` is a random identifier copied into every share of this file.
+/// - `nonce` is a 12-byte base nonce; per-chunk nonces are derived from it.
+/// - `cipher` is the AEAD the chunks are sealed with.
+/// - `compression` is applied to the input before it is encrypted.
+/// - `content` tells whether the payload is a single file or an archive of
+///   a directory tree.
+/// - `chunk_size` is the plaintext size of every chunk except the last one
+///   (0 for legacy single-stream files).
+/// - `ciphertext_len` is the size of the ciphertext in bytes, excluding tags;
+///   `None` for new files, whose length is recorded in a trailer after
+///   the last chunk and authenticated with it, since the input may be a
+///   stream or still growing when encryption starts.
+/// - `key_check` commits to the data key so a wrong key is rejected before
+///   any data is decrypted (see `crypto::key_check`).
+/// - `keyslots` each wrap the data key under an independent unlock method.
+///   They are excluded from the associated data (see `format::header_aad`)
+///   so slots can be added and removed without re-encrypting the payload;
+///   a forged slot cannot yield a key that passes `key_check`. Empty for
+///   legacy files, whose shares split the data key directly.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SafeHeader {
+    pub version: u8,
+    pub timestamp: u64,
+    pub label: String,
+    pub file_id: [u8; 16],
+    pub nonce: [u8; 12],
+    pub cipher: Cipher,
+    pub compression: Compression,
+    pub content: Content,
+    pub chunk_size: u32,
+    pub ciphertext_len: Option<u64>,
+    pub key_check: [u8; 32],
+    pub keyslots: Vec<Keyslot>,
+}
+
+/// AEAD sealing the chunks of a safefile.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Cipher {
+    /// Fastest with AES instructions; legacy files always use it.
+    #[default]
+    Aes256Gcm,
+    /// Fast in software, for CPUs without AES instructions.
+    ChaCha20Poly1305,
+    /// Resistant to nonce reuse, at some cost in speed.
+    Aes256GcmSiv,
+}
+
+impl Cipher {
+    pub const ALL: [Cipher; 3] = [
+        Cipher::Aes256Gcm,
+        Cipher::ChaCha20Poly1305,
+        Cipher::Aes256GcmSiv,
+    ];
+
+    /// Name used on the command line and in `info`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cipher::Aes256Gcm => "aes-256-gcm",
+            Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+            Cipher::Aes256GcmSiv => "aes-256-gcm-siv",
+        }
+    }
+}
+
+/// Compression applied to the input before encryption.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// What the plaintext of a safefile holds.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Content {
+    /// The bytes of a single file or stream.
+    #[default]
+    File,
+    /// A directory tree, see `archive`.
+    Archive,
+}
+
+impl fmt::Display for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Content::File => f.write_str("file"),
+            Content::Archive => f.write_str("archive"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Zstd => f.write_str("zstd"),
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = Error;
+
+    /// Parse a cipher name as printed by `name`, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Cipher::ALL
+            .into_iter()
+            .find(|c| c.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| Error::InvalidArgument {
+                details: format!(
+                    "unknown cipher '{}', expected one of: {}",
+                    s,
+                    Cipher::ALL.map(|c| c.name()).join(", ")
+                ),
+            })
+    }
+}
+
+/// Data key wrapped under one unlock method.
+///
+/// - `id` is a random identifier, also recorded in the slot's key shares.
+/// - `nonce` is the AES-GCM nonce used for wrapping.
+/// - `wrapped_key` is the encrypted data key followed by its tag.
+/// - `kind` describes where the wrapping key comes from.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Keyslot {
+    pub id: [u8; 8],
+    pub nonce: [u8; 12],
+    pub wrapped_key: Vec<u8>,
+    pub kind: KeyslotKind,
+}
+
+/// Source of a keyslot's wrapping key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum KeyslotKind {
+    /// A random key split into `shares` Shamir shares, `threshold` of which
+    /// are needed to reconstruct it.
+    Shamir { threshold: u8, shares: u8 },
+    /// A key derived from a password with Argon2id.
+    ///
+    /// - `kdf` holds the Argon2id parameters.
+    /// - `salt` is the random KDF salt.
+    Password { kdf: KdfParams, salt: [u8; 16] },
+    /// A random key split into Shamir shares, each sealed to a holder's
+    /// public key and stored here, `threshold` of which are needed.
+    Embedded {
+        threshold: u8,
+        shares: Vec<SealedShare>,
+    },
+}
+
+/// Argon2id cost parameters.
+///
+/// - `memory_kib` is the memory size in KiB.
+/// - `iterations` is the number of passes.
+/// - `parallelism` is the number of lanes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The second recommended option of RFC 9106: 64 MiB, 3 passes, 4 lanes.
+    fn default() -> Self {
+        KdfParams {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 4,
+        }
+    }
+}
+
+impl SafeHeader {
+    /// Whether this header was read from a version 1 file.
+    pub fn is_legacy(&self) -> bool {
+        self.version <= consts::LAST_STREAM_VERSION
+    }
+}
+
+/// Header of format version 1, which encrypts the whole payload as a single
+/// AES-GCM stream with one tag at the end.
+#[derive(Serialize, Deserialize)]
+pub struct LegacySafeHeader {
     pub version: u8,
     pub timestamp: u64,
     pub label: String,
@@ -18,11 +209,35 @@ pub struct SafeHeader {
     pub ciphertext_len: u64,
 }
 
+impl From<LegacySafeHeader> for SafeHeader {
+    fn from(h: LegacySafeHeader) -> Self {
+        SafeHeader {
+            version: h.version,
+            timestamp: h.timestamp,
+            label: h.label,
+            file_id: [0u8; 16],
+            nonce: h.nonce,
+            cipher: Cipher::Aes256Gcm,
+            compression: Compression::None,
+            content: Content::File,
+            chunk_size: 0,
+            ciphertext_len: Some(h.ciphertext_len),
+            key_check: [0u8; 32],
+            keyslots: Vec::new(),
+        }
+    }
+}
+
 /// Single key share written to disk.
 ///
 /// - `version` identifies the format version.
 /// - `timestamp` is UNIX seconds when the file was created.
 /// - `label` is an optional user label.
+/// - `file_id` is the `file_id` of the safefile this share belongs to.
+/// - `slot_id` is the `id` of the keyslot whose key this share splits.
+/// - `index` is the 1-based number of this share within its split.
+/// - `threshold` is the number of shares needed to reconstruct the key.
+/// - `total` is the number of shares created in the split.
 /// - `share` contains the raw share bytes.
 /// - `checksum` is a SHA-256 of the `share` for integrity verification.
 #[derive(Serialize, Deserialize)]
@@ -30,6 +245,131 @@ pub struct ShareFile {
     pub version: u8,
     pub timestamp: u64,
     pub label: String,
+    pub file_id: [u8; 16],
+    pub slot_id: [u8; 8],
+    pub index: u8,
+    pub threshold: u8,
+    pub total: u8,
     pub share: Vec<u8>,
     pub checksum: [u8; 32],
 }
+
+/// Share file of version 1, written before shares were bound to a safefile.
+///
+/// Converted shares have zero `slot_id`, `index`, `threshold` and `total`.
+#[derive(Serialize, Deserialize)]
+pub struct LegacyShareFile {
+    pub version: u8,
+    pub timestamp: u64,
+    pub label: String,
+    pub share: Vec<u8>,
+    pub checksum: [u8; 32],
+}
+
+impl From<LegacyShareFile> for ShareFile {
+    fn from(s: LegacyShareFile) -> Self {
+        ShareFile {
+            version: s.version,
+            timestamp: s.timestamp,
+            label: s.label,
+            file_id: [0u8; 16],
+            slot_id: [0u8; 8],
+            index: 0,
+            threshold: 0,
+            total: 0,
+            share: s.share,
+            checksum: s.checksum,
+        }
+    }
+}
+
+/// Share file sealed to a holder's public key.
+///
+/// - `version` identifies the format version.
+/// - `kind` is the public key algorithm the share is sealed with.
+/// - `recipient` is the X25519 public key the share is sealed to, or the
+///   SHA-256 of the whole public key for hybrid kinds.
+/// - `ephemeral` is the sender's ephemeral X25519 public key.
+/// - `ciphertext` is the encoded share file encrypted with AES-256-GCM
+///   under a key derived from the shared secrets, followed by its tag. For
+///   hybrid kinds it is preceded by the ML-KEM ciphertext.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SealedShare {
+    pub version: u8,
+    pub kind: RecipientKind,
+    pub recipient: [u8; 32],
+    pub ephemeral: [u8; 32],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Public key algorithm a share is sealed with.
+///
+/// Recorded with every sealed share so further kinds can be added without
+/// breaking existing files.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecipientKind {
+    /// X25519 key agreement, HKDF-SHA256 and AES-256-GCM.
+    X25519,
+    /// X25519 and ML-KEM-768 combined with HKDF-SHA256, then AES-256-GCM;
+    /// the share stays sealed as long as either of them holds.
+    MlKem768X25519,
+}
+
+impl RecipientKind {
+    pub const ALL: [RecipientKind; 2] = [RecipientKind::X25519, RecipientKind::MlKem768X25519];
+
+    /// Name used on the command line, in `info` and as the prefix of
+    /// public keys.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecipientKind::X25519 => "x25519",
+            RecipientKind::MlKem768X25519 => "mlkem768x25519",
+        }
+    }
+}
+
+impl FromStr for RecipientKind {
+    type Err = Error;
+
+    /// Parse a recipient kind name as printed by `name`, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        RecipientKind::ALL
+            .into_iter()
+            .find(|k| k.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| Error::InvalidArgument {
+                details: format!(
+                    "unknown recipient kind '{}', expected one of: {}",
+                    s,
+                    RecipientKind::ALL.map(|k| k.name()).join(", ")
+                ),
+            })
+    }
+}
+
+/// Sealed share of version 1, written before the recipient kind was
+/// recorded; every such share is sealed to an X25519 key.
+#[derive(Serialize, Deserialize)]
+pub struct LegacySealedShare {
+    pub version: u8,
+    pub recipient: [u8; 32],
+    pub ephemeral: [u8; 32],
+    pub ciphertext: Vec<u8>,
+}
+
+impl From<LegacySealedShare> for SealedShare {
+    fn from(s: LegacySealedShare) -> Self {
+        SealedShare {
+            version: s.version,
+            kind: RecipientKind::X25519,
+            recipient: s.recipient,
+            ephemeral: s.ephemeral,
+            ciphertext: s.ciphertext,
+        }
+    }
+}
+
+impl fmt::Display for RecipientKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -1,84 +1,699 @@
 use std::{
     fs::File,
-    io::{BufReader, BufWriter, Read, Write},
-    path::Path,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
-use crate::{container::SafeHeader, error::Error, format, stream_aes};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+use crate::{
+    archive::{self, EntryKind, IndexEntry},
+    atomic::AtomicFile,
+    consts,
+    container::{Cipher, Compression, Content, SafeHeader},
+    error::Error,
+    format::{self, Trailer},
+    stream::{self, ChunkCipher},
+};
 
 const REPORT_INTERVAL: Duration = Duration::from_millis(200);
 
-pub fn encrypt_stream<F>(
-    input: &Path,
-    output: &Path,
+/// How the payload is encrypted and decrypted.
+///
+/// - `threads` is the number of worker threads sealing or opening chunks in
+///   parallel; 0 uses one per available core.
+/// - `cipher` is the AEAD new files are encrypted with; existing files are
+///   decrypted with the one named in their header.
+/// - `compression_level` is the zstd level new files are compressed with
+///   before encryption, or `None` to store them uncompressed.
+#[derive(Clone, Copy, Default)]
+pub struct CryptoOptions {
+    pub threads: usize,
+    pub cipher: Cipher,
+    pub compression_level: Option<i32>,
+}
+
+impl CryptoOptions {
+    /// Start the worker pool.
+    fn pool(&self) -> Result<ThreadPool, Error> {
+        ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .thread_name(|i| format!("safefile-worker-{}", i))
+            .build()
+            .map_err(|e| Error::InternalError {
+                details: format!("failed to start worker threads: {}", e),
+            })
+    }
+}
+
+/// Read until `buf` is full or the reader is exhausted.
+///
+/// Returns the number of bytes read, which is less than `buf.len()` only at EOF.
+fn read_full<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Compute the key check value stored in the header.
+///
+/// A domain-separated SHA-256 of the data key, salted with the file ID. It
+/// commits the file to a single key, which plain GCM does not.
+pub fn key_check(key: &[u8; 32], file_id: &[u8; 16]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(consts::KEY_CHECK_CONTEXT);
+    hasher.update(file_id);
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+/// Check `key` against the header's key check value.
+///
+/// Legacy files have no key check and always pass; a wrong key is then only
+/// detected by the authentication tag.
+pub fn verify_key(header: &SafeHeader, key: &[u8; 32]) -> Result<(), Error> {
+    if header.is_legacy() {
+        return Ok(());
+    }
+    if key_check(key, &header.file_id) != header.key_check {
+        return Err(Error::WrongKey);
+    }
+    Ok(())
+}
+
+/// Number of bytes the chunked payload occupies on disk for `len` plaintext bytes.
+///
+/// The final chunk is always shorter than `chunk_size` (possibly empty), so
+/// there are `len / chunk_size + 1` chunks, each carrying a tag, followed
+/// by the trailer.
+fn chunked_payload_len(len: u64, chunk_size: u32) -> u64 {
+    len + (len / chunk_size as u64 + 1) * consts::TAG_SIZE as u64 + consts::TRAILER_LEN as u64
+}
+
+/// Encrypting writer producing a safefile.
+///
+/// The header is written on creation. Plaintext is compressed if the header
+/// asks for it, then sealed in batches of chunks, in parallel, as it is
+/// written. `finish` seals the last chunk and writes the trailer with the
+/// lengths written; without it the output is truncated and will not
+/// decrypt.
+pub struct EncryptingWriter<W: Write> {
+    sink: Sink<W>,
+    original_len: u64,
+}
+
+/// Where an `EncryptingWriter` sends plaintext.
+enum Sink<W: Write> {
+    Plain(Sealer<W>),
+    Zstd(zstd::stream::write::Encoder<'static, Sealer<W>>),
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Write `header` to `inner` and encrypt with `header.cipher` under `key`
+    /// and `header.nonce`.
+    pub fn new(
+        inner: W,
+        key: [u8; 32],
+        header: &SafeHeader,
+        options: &CryptoOptions,
+    ) -> Result<Self, Error> {
+        let sealer = Sealer::new(inner, key, header, options)?;
+        let sink = match header.compression {
+            Compression::None => Sink::Plain(sealer),
+            Compression::Zstd => {
+                let level = options.compression_level.unwrap_or(consts::ZSTD_LEVEL);
+                let threads = sealer.pool.current_num_threads() as u32;
+                let mut encoder = zstd::stream::write::Encoder::new(sealer, level)?;
+                encoder.multithread(threads)?;
+                Sink::Zstd(encoder)
+            }
+        };
+
+        Ok(Self {
+            sink,
+            original_len: 0,
+        })
+    }
+
+    /// Seal the final chunk, write the trailer, flush and return the inner
+    /// writer.
+    pub fn finish(self) -> Result<W, Error> {
+        let sealer = match self.sink {
+            Sink::Plain(sealer) => sealer,
+            Sink::Zstd(encoder) => encoder.finish()?,
+        };
+        sealer.finish(self.original_len)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let n = match &mut self.sink {
+            Sink::Plain(sealer) => sealer.write(data)?,
+            Sink::Zstd(encoder) => encoder.write(data)?,
+        };
+        self.original_len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.sink {
+            Sink::Plain(sealer) => sealer.flush(),
+            Sink::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Seals the payload of an `EncryptingWriter`, after any compression.
+struct Sealer<W: Write> {
+    inner: W,
+    enc: Box<dyn ChunkCipher>,
+    pool: ThreadPool,
+    buf: Vec<u8>,
+    chunk_size: usize,
+    batch_size: usize,
+    written: u64,
+}
+
+impl<W: Write> Sealer<W> {
+    fn new(
+        mut inner: W,
+        key: [u8; 32],
+        header: &SafeHeader,
+        options: &CryptoOptions,
+    ) -> Result<Self, Error> {
+        if header.chunk_size == 0 {
+            return Err(Error::InvalidArgument {
+                details: "chunk size must not be zero".into(),
+            });
+        }
+
+        // The header is bound to every chunk as associated data, so any change
+        // to the label, timestamp or length invalidates the tags.
+        format::write_header(&mut inner, header)?;
+        let aad = format::header_aad(header)?;
+        let pool = options.pool()?;
+        let chunk_size = header.chunk_size as usize;
+        let batch_size = chunk_size * pool.current_num_threads() * consts::CHUNKS_PER_THREAD;
+
+        Ok(Self {
+            inner,
+            enc: stream::chunk_cipher(header.cipher, key, &header.nonce, &aad),
+            pool,
+            buf: Vec::with_capacity(batch_size),
+            chunk_size,
+            batch_size,
+            written: 0,
+        })
+    }
+
+    /// Seal the final chunk, then write the trailer recording the payload
+    /// and `original_len`, the length before compression.
+    fn finish(mut self, original_len: u64) -> Result<W, Error> {
+        // The final chunk authenticates the trailer.
+        let trailer = format::encode_trailer(&Trailer {
+            payload_len: self.written,
+            original_len,
+        });
+        self.enc.bind_trailer(&trailer);
+        let res = self.seal(true);
+        self.buf.zeroize();
+        res?;
+        self.inner.write_all(&trailer)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Seal the buffered chunks in parallel and write them out in order.
+    ///
+    /// Unless `last`, the buffer holds full chunks only. The final chunk is
+    /// always shorter than a full one, possibly empty.
+    fn seal(&mut self, last: bool) -> Result<(), Error> {
+        let mut chunks: Vec<&[u8]> = self.buf.chunks(self.chunk_size).collect();
+        if last && self.buf.len().is_multiple_of(self.chunk_size) {
+            chunks.push(&[]);
+        }
+
+        let enc = &mut self.enc;
+        let cts = self.pool.install(|| enc.encrypt_chunks(&chunks, last))?;
+        for ct in cts {
+            self.inner.write_all(&ct)?;
+        }
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Sealer<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let n = data.len().min(self.batch_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        self.written += n as u64;
+
+        if self.buf.len() == self.batch_size {
+            self.seal(false)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypting reader over the payload of a chunked safefile.
+///
+/// Chunks are opened in parallel batches, and each is returned only once
+/// its tag verifies, so data read before an error is authentic; an error
+/// still means the file as a whole is not. Compressed payloads are
+/// decompressed, and the lengths in the trailer are checked once the last
+/// chunk has verified.
+pub struct DecryptingReader<R: Read> {
+    source: Source<R>,
+    path: PathBuf,
+    original_len: u64,
+}
+
+/// Where a `DecryptingReader` reads plaintext from.
+enum Source<R: Read> {
+    Plain(Opener<R>),
+    Zstd(zstd::stream::read::Decoder<'static, BufReader<Opener<R>>>),
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// Decrypt the payload of `inner` with `key`.
+    ///
+    /// `inner` must be positioned after the header, as `format::read_header`
+    /// leaves it, and `path` names it in errors. Version 1 files only
+    /// authenticate at their end and cannot be read this way, see `decrypt`.
+    pub fn new(
+        inner: R,
+        key: [u8; 32],
+        header: &SafeHeader,
+        path: &Path,
+        options: &CryptoOptions,
+    ) -> Result<Self, Error> {
+        let opener = Opener::new(inner, key, header, path, options)?;
+        let source = match header.compression {
+            Compression::None => Source::Plain(opener),
+            Compression::Zstd => Source::Zstd(zstd::stream::read::Decoder::new(opener)?),
+        };
+
+        Ok(Self {
+            source,
+            path: path.to_path_buf(),
+            original_len: 0,
+        })
+    }
+
+    /// Number of payload bytes read so far, tags included.
+    pub fn consumed(&self) -> u64 {
+        self.opener().consumed
+    }
+
+    fn opener(&self) -> &Opener<R> {
+        match &self.source {
+            Source::Plain(opener) => opener,
+            Source::Zstd(decoder) => decoder.get_ref().get_ref(),
+        }
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let n = match &mut self.source {
+            Source::Plain(opener) => opener.read(out)?,
+            Source::Zstd(decoder) => decoder.read(out)?,
+        };
+        self.original_len += n as u64;
+
+        // The payload only ends once the last chunk and the trailer verified.
+        let ended = n == 0 && !out.is_empty();
+        if ended && self.opener().trailer.map(|t| t.original_len) != Some(self.original_len) {
+            return Err(Error::InvalidFormat {
+                path: self.path.clone(),
+                details: "original length does not match the trailer".into(),
+            }
+            .into());
+        }
+        Ok(n)
+    }
+}
+
+/// Opens the payload of a `DecryptingReader`, before any decompression.
+struct Opener<R: Read> {
+    inner: R,
+    dec: Box<dyn ChunkCipher>,
+    pool: ThreadPool,
+    path: PathBuf,
+    chunk_len: usize,
+    /// A batch of chunks, read ahead by the trailer size so the last chunk
+    /// is known once read.
+    buf: Vec<u8>,
+    held: usize,
+    plain: Vec<u8>,
+    pos: usize,
+    consumed: u64,
+    produced: u64,
+    /// Set once the last chunk has verified.
+    trailer: Option<Trailer>,
+}
+
+impl<R: Read> Opener<R> {
+    fn new(
+        inner: R,
+        key: [u8; 32],
+        header: &SafeHeader,
+        path: &Path,
+        options: &CryptoOptions,
+    ) -> Result<Self, Error> {
+        if header.is_legacy() {
+            return Err(Error::InvalidFormat {
+                path: path.to_path_buf(),
+                details: "version 1 files cannot be read incrementally".into(),
+            });
+        }
+        if header.chunk_size == 0 {
+            return Err(Error::InvalidFormat {
+                path: path.to_path_buf(),
+                details: "invalid chunk size".into(),
+            });
+        }
+
+        let aad = format::header_aad(header)?;
+        let pool = options.pool()?;
+        let chunk_len = header.chunk_size as usize + consts::TAG_SIZE;
+        let batch_len = chunk_len * pool.current_num_threads() * consts::CHUNKS_PER_THREAD;
+
+        Ok(Self {
+            inner,
+            dec: stream::chunk_cipher(header.cipher, key, &header.nonce, &aad),
+            pool,
+            path: path.to_path_buf(),
+            chunk_len,
+            buf: vec![0u8; batch_len + consts::TRAILER_LEN],
+            held: 0,
+            plain: Vec::new(),
+            pos: 0,
+            consumed: 0,
+            produced: 0,
+            trailer: None,
+        })
+    }
+
+    /// Decrypt the next batch of chunks into `plain`; returns false after
+    /// the last one.
+    fn next_batch(&mut self) -> Result<bool, Error> {
+        self.plain.zeroize();
+        self.pos = 0;
+        if self.trailer.is_some() {
+            return Ok(false);
+        }
+
+        let n = self.held + read_full(&mut self.inner, &mut self.buf[self.held..])?;
+        let batch_len = self.buf.len() - consts::TRAILER_LEN;
+
+        if n == self.buf.len() {
+            // A full batch never holds the last chunk, and at least the
+            // trailer follows it; keep the bytes read ahead for the next one.
+            self.open(batch_len, false)?;
+            self.buf.copy_within(batch_len.., 0);
+            self.held = consts::TRAILER_LEN;
+            self.consumed += batch_len as u64;
+            return Ok(true);
+        }
+
+        // Running out of data before a chunk flagged as last means truncation.
+        if n < consts::TAG_SIZE + consts::TRAILER_LEN {
+            return Err(Error::IncompleteFile {
+                path: self.path.clone(),
+            });
+        }
+        let end = n - consts::TRAILER_LEN;
+        let trailer = format::decode_trailer(&self.buf[end..n], &self.path)?;
+        self.dec.bind_trailer(&self.buf[end..n]);
+        self.open(end, true)?;
+        self.consumed += n as u64;
+
+        if trailer.payload_len != self.produced {
+            return Err(Error::InvalidFormat {
+                path: self.path.clone(),
+                details: "payload length does not match the trailer".into(),
+            });
+        }
+        self.trailer = Some(trailer);
+        Ok(true)
+    }
+
+    /// Open the chunks in `buf[..end]` in parallel into `plain`.
+    fn open(&mut self, end: usize, last: bool) -> Result<(), Error> {
+        let chunks: Vec<&[u8]> = self.buf[..end].chunks(self.chunk_len).collect();
+        let dec = &mut self.dec;
+        let pts = self.pool.install(|| dec.decrypt_chunks(&chunks, last))?;
+
+        self.plain = pts.concat();
+        for mut pt in pts {
+            pt.zeroize();
+        }
+        self.produced += self.plain.len() as u64;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Opener<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        // Loop past empty batches, which only the final chunk can make.
+        while self.pos == self.plain.len() {
+            if !self.next_batch()? {
+                return Ok(0);
+            }
+        }
+
+        let n = out.len().min(self.plain.len() - self.pos);
+        out[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read> Drop for Opener<R> {
+    fn drop(&mut self) {
+        self.plain.zeroize();
+    }
+}
+
+/// Encrypt everything `reader` yields into `writer` as a safefile.
+///
+/// The payload is encrypted under `key` and `header.nonce`. If the header
+/// asks for compression but the start of the input barely compresses, as
+/// with media or archives, it is stored uncompressed instead. Returns
+/// `writer` once flushed.
+pub fn encrypt<R, W, F>(
+    reader: &mut R,
+    writer: W,
     key: [u8; 32],
-    nonce: [u8; 12],
-    header: SafeHeader,
+    header: &SafeHeader,
+    options: &CryptoOptions,
     mut progress_callback: F,
-) -> Result<(), Error>
+) -> Result<W, Error>
 where
+    R: Read + ?Sized,
+    W: Write,
     F: FnMut(u64, u64),
 {
-    let mut reader = BufReader::new(File::open(input)?);
-    let mut writer = BufWriter::new(File::create(output)?);
+    let mut buf = vec![0u8; header.chunk_size as usize];
+    let mut n = read_full(reader, &mut buf)?;
 
-    let file_size = reader.get_ref().metadata()?.len();
+    let mut header = header.clone();
+    if header.compression != Compression::None && !compressible(&buf[..n]) {
+        header.compression = Compression::None;
+    }
+    let mut enc = EncryptingWriter::new(writer, key, &header, options)?;
     let mut processed: u64 = 0;
     let mut last_report = Instant::now();
 
-    format::write_header(&mut writer, &header)?;
+    loop {
+        enc.write_all(&buf[..n])?;
+        processed += n as u64;
 
-    let mut enc = stream_aes::Encryptor::new(key, &nonce);
-    let mut buf = vec![0u8; 1024 * 1024];
+        let last = n < buf.len();
+        if last_report.elapsed() >= REPORT_INTERVAL || last {
+            // The length of the input is not known up front.
+            progress_callback(processed, 0);
+            last_report = Instant::now();
+        }
 
-    loop {
-        let n = reader.read(&mut buf)?;
-        if n == 0 {
+        if last {
             break;
         }
+        n = read_full(reader, &mut buf)?;
+    }
 
-        let ct = enc.update(&buf[..n]);
-        writer.write_all(&ct)?;
-
-        processed += n as u64;
+    enc.finish()
+}
 
-        if last_report.elapsed() >= REPORT_INTERVAL || processed == file_size {
-            progress_callback(processed, file_size);
-            last_report = Instant::now();
+/// Whether `sample`, the start of the input, is worth compressing: it must
+/// shrink by at least `consts::MIN_COMPRESSION_SAVING` percent at a fast
+/// level.
+fn compressible(sample: &[u8]) -> bool {
+    match zstd::bulk::compress(sample, 1) {
+        Ok(mut compressed) => {
+            let worth = (compressed.len() as u64) * 100
+                < sample.len() as u64 * (100 - consts::MIN_COMPRESSION_SAVING);
+            compressed.zeroize();
+            worth
         }
+        Err(_) => false,
     }
+}
 
-    let (last_block, tag) = enc.finalize();
-    writer.write_all(&last_block)?;
-    writer.write_all(&tag)?;
-    writer.flush()?;
+/// Decrypt the payload of `reader` into `writer`.
+///
+/// `reader` must be positioned after the header, as `format::read_header`
+/// leaves it with `header`; `path` names it in errors.
+/// On error `writer` holds unverified or partial data and must be discarded.
+pub fn decrypt<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    key: [u8; 32],
+    header: &SafeHeader,
+    path: &Path,
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<(), Error>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+    F: FnMut(u64, u64),
+{
+    if header.is_legacy() {
+        decrypt_legacy(reader, writer, path, header, key, progress_callback)
+    } else {
+        // The trailer holding the length is only reached at the end.
+        let dec = DecryptingReader::new(reader, key, header, path, options)?;
+        decrypt_chunked(dec, writer, 0, progress_callback)
+    }
+}
+
+/// Encrypt `input` into the staged `output`.
+///
+/// The caller decides whether to commit `output` once this returns.
+pub fn encrypt_stream<F>(
+    input: &Path,
+    output: &mut AtomicFile,
+    key: [u8; 32],
+    header: SafeHeader,
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<(), Error>
+where
+    F: FnMut(u64, u64),
+{
+    let file = File::open(input)?;
+    // The size is only an estimate for progress: the input may still be
+    // growing, or be a device or pipe without a size.
+    let total = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut progress_callback = progress_callback;
+    let writer = encrypt(
+        &mut reader,
+        BufWriter::new(output),
+        key,
+        &header,
+        options,
+        |done, _| progress_callback(done, total),
+    )?;
+    drop(writer);
 
     Ok(())
 }
 
-pub fn decrypt_stream<F>(
+/// Decrypt `input` into `output`, usually a staged `AtomicFile`.
+///
+/// Returns only once every tag has verified; on error `output` holds
+/// unverified or partial data and must not be committed.
+pub fn decrypt_stream<W, F>(
     input: &Path,
-    output: &Path,
+    output: &mut W,
     key: [u8; 32],
-    mut progress_callback: F,
+    options: &CryptoOptions,
+    progress_callback: F,
 ) -> Result<(), Error>
 where
+    W: Write + ?Sized,
     F: FnMut(u64, u64),
 {
-    let mut reader = BufReader::new(File::open(input)?);
-    let mut writer = BufWriter::new(File::create(output)?);
+    let Payload {
+        mut reader,
+        header,
+        total_crypto_len,
+        ..
+    } = open_payload(input)?;
 
-    let (header, header_len) = format::read_header(&mut reader, input.into())?;
-    let nonce = header.nonce;
+    let mut writer = BufWriter::new(output);
+    if header.is_legacy() {
+        decrypt_legacy(
+            &mut reader,
+            &mut writer,
+            input,
+            &header,
+            key,
+            progress_callback,
+        )
+    } else {
+        let dec = DecryptingReader::new(&mut reader, key, &header, input, options)?;
+        decrypt_chunked(dec, &mut writer, total_crypto_len, progress_callback)
+    }
+}
 
-    let file_size = reader.get_ref().metadata()?.len();
+/// A safefile opened for decryption.
+///
+/// `reader` is positioned after the header, whose raw bytes are
+/// `raw_header`; `total_crypto_len` is the payload length including tags.
+struct Payload {
+    reader: BufReader<Box<dyn format::ReadSeek>>,
+    header: SafeHeader,
+    raw_header: Vec<u8>,
+    total_crypto_len: u64,
+}
+
+/// Open the safefile at `input` and check its size against the payload
+/// length.
+fn open_payload(input: &Path) -> Result<Payload, Error> {
+    let (file, file_size) = format::open_safe(input)?;
+    let mut reader = BufReader::new(file);
+
+    let (header, raw_header) = format::read_header(&mut reader, input.into())?;
 
     // Expected data position: magic (4) + header length (4) + header bytes
-    let data_start = 8 + header_len as u64;
-    // Ciphertext length read from the header + 16-byte tag
-    let total_crypto_len = header.ciphertext_len as u64 + 16;
+    let data_start = raw_header.len() as u64;
 
+    if header.chunk_size == 0 && !header.is_legacy() {
+        return Err(Error::InvalidFormat {
+            path: input.to_path_buf(),
+            details: "invalid chunk size".into(),
+        });
+    }
+    let total_crypto_len = match header.ciphertext_len {
+        // Ciphertext length read from the legacy header + 16-byte tag
+        Some(len) if header.is_legacy() => len + consts::TAG_SIZE as u64,
+        // Otherwise the trailer holds the length; it is authenticated along
+        // with the last chunk during decryption.
+        _ => chunked_payload_len(
+            format::read_trailer(&mut reader, input)?.payload_len,
+            header.chunk_size,
+        ),
+    };
     if file_size != data_start + total_crypto_len {
         return Err(Error::InvalidFormat {
             path: input.to_path_buf(),
@@ -86,8 +701,536 @@ where
         });
     }
 
-    let mut dec = stream_aes::Decryptor::new(key, &nonce);
+    Ok(Payload {
+        reader,
+        header,
+        raw_header,
+        total_crypto_len,
+    })
+}
 
+/// Archive the tree under `dir` into the staged `output`.
+///
+/// `header.content` should be `Content::Archive`. Compression is used as
+/// the header asks, without sampling the input. The caller decides whether
+/// to commit `output` once this returns.
+pub fn encrypt_tree<F>(
+    dir: &Path,
+    output: &mut AtomicFile,
+    key: [u8; 32],
+    header: SafeHeader,
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<(), Error>
+where
+    F: FnMut(u64, u64),
+{
+    let enc = EncryptingWriter::new(BufWriter::new(output), key, &header, options)?;
+    // The size of a tree is not known up front.
+    let reporting = Reporting {
+        inner: enc,
+        done: 0,
+        total: 0,
+        last_report: Instant::now(),
+        progress_callback,
+    };
+    let enc = archive::write_tree(dir, reporting)?.inner;
+    drop(enc.finish()?);
+
+    Ok(())
+}
+
+/// Decrypt the archive in `input` and unpack its tree into the existing
+/// directory `dest`, usually a staged `AtomicDir`.
+///
+/// Returns only once every tag has verified; on error `dest` holds
+/// unverified or partial data and must not be committed.
+pub fn decrypt_tree<F>(
+    input: &Path,
+    dest: &Path,
+    key: [u8; 32],
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<(), Error>
+where
+    F: FnMut(u64, u64),
+{
+    let Payload {
+        mut reader,
+        header,
+        total_crypto_len,
+        ..
+    } = open_payload(input)?;
+    if header.content != Content::Archive {
+        return Err(Error::InvalidArgument {
+            details: format!("'{}' does not hold a directory archive", input.display()),
+        });
+    }
+
+    let dec = DecryptingReader::new(&mut reader, key, &header, input, options)?;
+    let reporting = Reporting {
+        inner: dec,
+        done: 0,
+        total: total_crypto_len,
+        last_report: Instant::now(),
+        progress_callback,
+    };
+    let mut rest = archive::unpack_tree(reporting, dest, input)?;
+
+    // Only the end of the payload authenticates the whole of it.
+    std::io::copy(&mut rest, &mut std::io::sink())?;
+    (rest.progress_callback)(rest.inner.consumed(), total_crypto_len);
+
+    Ok(())
+}
+
+/// Entries of the archive in `input`.
+///
+/// Uncompressed archives are listed from their index, decrypting only the
+/// chunks that hold it. Compressed ones are decrypted whole and their
+/// entries read in order.
+pub fn list_tree(
+    input: &Path,
+    key: [u8; 32],
+    options: &CryptoOptions,
+) -> Result<Vec<IndexEntry>, Error> {
+    let header = format::read_header_from_path(input)?.0;
+    check_archive(input, &header)?;
+    if header.compression == Compression::None {
+        let mut reader = SeekableReader::open(input, key)?;
+        return read_index(&mut reader, input);
+    }
+
+    let mut entries = Vec::new();
+    scan_compressed_tree(input, key, options, |entry, _| {
+        entries.push(entry.clone());
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Decrypt the file at `name` in the archive `input` into `output` and
+/// return its entry.
+///
+/// Only the chunks holding the index and the file are decrypted, unless the
+/// archive is compressed. Progress is reported against the size of the
+/// file. On error `output` holds partial data and must be discarded.
+pub fn extract_from_tree<W, F>(
+    input: &Path,
+    name: &str,
+    output: &mut W,
+    key: [u8; 32],
+    options: &CryptoOptions,
+    mut progress_callback: F,
+) -> Result<IndexEntry, Error>
+where
+    W: Write + ?Sized,
+    F: FnMut(u64, u64),
+{
+    let header = format::read_header_from_path(input)?.0;
+    check_archive(input, &header)?;
+
+    let found = if header.compression == Compression::None {
+        let mut reader = SeekableReader::open(input, key)?;
+        let index = read_index(&mut reader, input)?;
+        let found = index.into_iter().find(|e| e.is_at(name));
+        if let Some(entry) = found.as_ref().filter(|e| e.kind == EntryKind::File) {
+            reader.copy_range(entry.offset, entry.size, output, &mut progress_callback)?;
+        }
+        found
+    } else {
+        let mut found = None;
+        scan_compressed_tree(input, key, options, |entry, data| {
+            if found.is_none() && entry.is_at(name) {
+                if entry.kind == EntryKind::File {
+                    std::io::copy(data, output)?;
+                    progress_callback(entry.size, entry.size);
+                }
+                found = Some(entry.clone());
+            }
+            Ok(())
+        })?;
+        found
+    };
+
+    match found {
+        Some(entry) if entry.kind == EntryKind::File => {
+            output.flush()?;
+            Ok(entry)
+        }
+        Some(_) => Err(Error::InvalidArgument {
+            details: format!("'{}' is not a file in the archive", name),
+        }),
+        None => Err(Error::InvalidArgument {
+            details: format!("'{}' is not in the archive", name),
+        }),
+    }
+}
+
+fn check_archive(input: &Path, header: &SafeHeader) -> Result<(), Error> {
+    if header.content != Content::Archive {
+        return Err(Error::InvalidArgument {
+            details: format!("'{}' does not hold a directory archive", input.display()),
+        });
+    }
+    Ok(())
+}
+
+/// Read the index at the end of the plaintext of an uncompressed archive.
+fn read_index(reader: &mut SeekableReader, input: &Path) -> Result<Vec<IndexEntry>, Error> {
+    let len = reader.len();
+    let footer_len = consts::INDEX_FOOTER_LEN as u64;
+    let missing = || Error::InvalidFormat {
+        path: input.to_path_buf(),
+        details: "missing archive index".into(),
+    };
+    if len < footer_len {
+        return Err(missing());
+    }
+
+    let mut footer = [0u8; consts::INDEX_FOOTER_LEN];
+    reader.read_exact_at(len - footer_len, &mut footer)?;
+    let index_len = archive::index_len(&footer, input)?;
+    if index_len > len - footer_len {
+        return Err(missing());
+    }
+
+    let mut raw = vec![0u8; index_len as usize];
+    reader.read_exact_at(len - footer_len - index_len, &mut raw)?;
+    archive::decode_index(&raw, input)
+}
+
+/// Decrypt a compressed archive whole, calling `f` with each entry in
+/// order as `archive::scan_tree` does.
+fn scan_compressed_tree<F>(
+    input: &Path,
+    key: [u8; 32],
+    options: &CryptoOptions,
+    f: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&IndexEntry, &mut dyn Read) -> Result<(), Error>,
+{
+    let Payload {
+        mut reader, header, ..
+    } = open_payload(input)?;
+    let dec = DecryptingReader::new(&mut reader, key, &header, input, options)?;
+    let mut rest = archive::scan_tree(dec, input, f)?;
+
+    // Only the end of the payload authenticates the whole of it.
+    std::io::copy(&mut rest, &mut std::io::sink())?;
+    Ok(())
+}
+
+/// Decrypting reader with random access to an uncompressed chunked
+/// safefile.
+///
+/// Only the chunks read are decrypted, each verified on its own, so data is
+/// authentic as soon as it is returned. The last chunk is verified on
+/// opening, which authenticates the trailer and so the length of the
+/// plaintext. Seeking past the end is allowed; reads there return nothing.
+pub struct SeekableReader {
+    reader: BufReader<Box<dyn format::ReadSeek>>,
+    dec: Box<dyn ChunkCipher>,
+    path: PathBuf,
+    data_start: u64,
+    chunk_size: u64,
+    len: u64,
+    pos: u64,
+    /// Index and plaintext of the chunk decrypted last.
+    cached: Option<(u64, Vec<u8>)>,
+}
+
+impl SeekableReader {
+    /// Open the safefile at `input` with `key`.
+    ///
+    /// Version 1 files and compressed files cannot be read at random
+    /// and are rejected.
+    pub fn open(input: &Path, key: [u8; 32]) -> Result<Self, Error> {
+        let Payload {
+            mut reader,
+            header,
+            raw_header,
+            ..
+        } = open_payload(input)?;
+        if header.is_legacy() {
+            return Err(Error::InvalidArgument {
+                details: "version 1 safefiles can only be decrypted whole".into(),
+            });
+        }
+        if header.compression != Compression::None {
+            return Err(Error::InvalidArgument {
+                details: "compressed safefiles can only be decrypted whole".into(),
+            });
+        }
+
+        let trailer = format::read_trailer(&mut reader, input)?;
+        let aad = format::header_aad(&header)?;
+        let mut dec = stream::chunk_cipher(header.cipher, key, &header.nonce, &aad);
+        dec.bind_trailer(&format::encode_trailer(&trailer));
+
+        let mut chunks = Self {
+            reader,
+            dec,
+            path: input.to_path_buf(),
+            data_start: raw_header.len() as u64,
+            chunk_size: header.chunk_size as u64,
+            len: trailer.payload_len,
+            pos: 0,
+            cached: None,
+        };
+        chunks.load(chunks.last_chunk())?;
+        Ok(chunks)
+    }
+
+    /// Length of the plaintext.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the plaintext is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn last_chunk(&self) -> u64 {
+        self.len / self.chunk_size
+    }
+
+    /// Decrypt chunk `index` into the cache, unless it is there already.
+    fn load(&mut self, index: u64) -> Result<(), Error> {
+        if self.cached.as_ref().is_some_and(|(i, _)| *i == index) {
+            return Ok(());
+        }
+
+        let plain_len = self.chunk_size.min(self.len - index * self.chunk_size);
+        let mut ct = vec![0u8; plain_len as usize + consts::TAG_SIZE];
+        let pos = self.data_start + index * (self.chunk_size + consts::TAG_SIZE as u64);
+        self.reader.seek(SeekFrom::Start(pos))?;
+        self.reader
+            .read_exact(&mut ct)
+            .map_err(|_e| Error::IncompleteFile {
+                path: self.path.clone(),
+            })?;
+
+        self.dec.seek(index);
+        let mut plain = self.dec.decrypt_chunks(&[&ct], index == self.last_chunk())?;
+        if let Some((_, mut old)) = self.cached.take() {
+            old.zeroize();
+        }
+        self.cached = plain.pop().map(|p| (index, p));
+        Ok(())
+    }
+
+    /// Read plaintext at `offset` into `out`, returning the number of bytes
+    /// read, which is 0 only at the end.
+    pub(crate) fn read_at(&mut self, offset: u64, out: &mut [u8]) -> Result<usize, Error> {
+        if offset >= self.len || out.is_empty() {
+            return Ok(0);
+        }
+
+        let index = offset / self.chunk_size;
+        self.load(index)?;
+        let (_, plain) = self.cached.as_ref().expect("chunk was just loaded");
+        let at = (offset - index * self.chunk_size) as usize;
+        let n = out.len().min(plain.len() - at);
+        out[..n].copy_from_slice(&plain[at..at + n]);
+        Ok(n)
+    }
+
+    /// Fill `out` with the plaintext at `offset`.
+    pub(crate) fn read_exact_at(&mut self, offset: u64, out: &mut [u8]) -> Result<(), Error> {
+        let mut done = 0;
+        while done < out.len() {
+            let n = self.read_at(offset + done as u64, &mut out[done..])?;
+            if n == 0 {
+                return Err(Error::IncompleteFile {
+                    path: self.path.clone(),
+                });
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Write `len` bytes of plaintext at `offset` to `writer`, reporting
+    /// progress against `len`.
+    pub(crate) fn copy_range<W, F>(
+        &mut self,
+        offset: u64,
+        len: u64,
+        writer: &mut W,
+        progress_callback: &mut F,
+    ) -> Result<(), Error>
+    where
+        W: Write + ?Sized,
+        F: FnMut(u64, u64),
+    {
+        let mut buf = vec![0u8; self.chunk_size as usize];
+        let mut done = 0;
+        let mut last_report = Instant::now();
+
+        let res = loop {
+            if done == len {
+                progress_callback(done, len);
+                break Ok(());
+            }
+            let want = buf.len().min((len - done) as usize);
+            if let Err(e) = self.read_exact_at(offset + done, &mut buf[..want]) {
+                break Err(e);
+            }
+            if let Err(e) = writer.write_all(&buf[..want]) {
+                break Err(e.into());
+            }
+            done += want as u64;
+
+            if last_report.elapsed() >= REPORT_INTERVAL {
+                progress_callback(done, len);
+                last_report = Instant::now();
+            }
+        };
+
+        buf.zeroize();
+        res
+    }
+}
+
+impl Read for SeekableReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.read_at(self.pos, out)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SeekableReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the plaintext",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl Drop for SeekableReader {
+    fn drop(&mut self) {
+        if let Some((_, plain)) = self.cached.as_mut() {
+            plain.zeroize();
+        }
+    }
+}
+
+/// Reports progress to `progress_callback` at most every `REPORT_INTERVAL`
+/// while plaintext is written to an `EncryptingWriter` or read from a
+/// `DecryptingReader`.
+struct Reporting<T, F> {
+    inner: T,
+    done: u64,
+    total: u64,
+    last_report: Instant,
+    progress_callback: F,
+}
+
+impl<T, F: FnMut(u64, u64)> Reporting<T, F> {
+    fn report(&mut self, done: u64) {
+        if self.last_report.elapsed() >= REPORT_INTERVAL {
+            (self.progress_callback)(done, self.total);
+            self.last_report = Instant::now();
+        }
+    }
+}
+
+impl<W: Write, F: FnMut(u64, u64)> Write for Reporting<EncryptingWriter<W>, F> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(data)?;
+        self.done += n as u64;
+        self.report(self.done);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read, F: FnMut(u64, u64)> Read for Reporting<DecryptingReader<R>, F> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.report(self.inner.consumed());
+        Ok(n)
+    }
+}
+
+/// Decrypt a chunked payload, writing each chunk only once its tag verifies.
+fn decrypt_chunked<R, W, F>(
+    mut dec: DecryptingReader<R>,
+    writer: &mut W,
+    total_crypto_len: u64,
+    mut progress_callback: F,
+) -> Result<(), Error>
+where
+    R: Read,
+    W: Write + ?Sized,
+    F: FnMut(u64, u64),
+{
+    let mut buf = vec![0u8; consts::CHUNK_SIZE as usize];
+    let mut last_report = Instant::now();
+
+    let res = loop {
+        let n = match dec.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => break Err(e.into()),
+        };
+        if n == 0 {
+            progress_callback(dec.consumed(), total_crypto_len);
+            break writer.flush().map_err(Error::from);
+        }
+        if let Err(e) = writer.write_all(&buf[..n]) {
+            break Err(e.into());
+        }
+
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            progress_callback(dec.consumed(), total_crypto_len);
+            last_report = Instant::now();
+        }
+    };
+
+    buf.zeroize();
+    res
+}
+
+/// Decrypt a version 1 payload encrypted as one GCM stream.
+fn decrypt_legacy<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    input: &Path,
+    header: &SafeHeader,
+    key: [u8; 32],
+    mut progress_callback: F,
+) -> Result<(), Error>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+    F: FnMut(u64, u64),
+{
+    // Version 1 files do not authenticate the header.
+    let mut dec = stream::LegacyDecryptor::new(key, &header.nonce);
+
+    let Some(len) = header.ciphertext_len else {
+        return Err(Error::InvalidFormat {
+            path: input.to_path_buf(),
+            details: "missing payload length".into(),
+        });
+    };
+    let total_crypto_len = len + consts::TAG_SIZE as u64;
     let mut remaining = total_crypto_len;
     let mut buf = vec![0u8; 1024 * 1024];
     let mut processed: u64 = 0;
@@ -119,3 +1262,148 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const CHUNK: usize = consts::CHUNK_SIZE as usize;
+    /// Size of a full chunk in the file, tag included.
+    const SEALED_CHUNK: usize = CHUNK + consts::TAG_SIZE;
+
+    fn header(cipher: Cipher) -> SafeHeader {
+        SafeHeader {
+            version: consts::VERSION,
+            timestamp: 0,
+            label: "test".into(),
+            file_id: [1; 16],
+            nonce: [2; 12],
+            cipher,
+            compression: Compression::None,
+            content: Content::File,
+            chunk_size: consts::CHUNK_SIZE,
+            ciphertext_len: None,
+            key_check: key_check(&KEY, &[1; 16]),
+            keyslots: Vec::new(),
+        }
+    }
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn seal(cipher: Cipher, plain: &[u8]) -> Vec<u8> {
+        let options = CryptoOptions::default();
+        encrypt(&mut &plain[..], Vec::new(), KEY, &header(cipher), &options, |_, _| {}).unwrap()
+    }
+
+    /// Decrypt a whole safefile, returning the plaintext.
+    fn open(file: &[u8]) -> Result<Vec<u8>, Error> {
+        let path = Path::new("test.safe");
+        let mut reader = file;
+        let (header, _raw) = format::read_header(&mut reader, path.into())?;
+        let mut plain = Vec::new();
+        let options = CryptoOptions::default();
+        decrypt(&mut reader, &mut plain, KEY, &header, path, &options, |_, _| {})?;
+        Ok(plain)
+    }
+
+    /// Offset of the first chunk in `file`.
+    fn payload_start(file: &[u8]) -> usize {
+        format::read_header(&mut &file[..], "test.safe".into()).unwrap().1.len()
+    }
+
+    #[test]
+    fn round_trip_around_chunk_size() {
+        for cipher in Cipher::ALL {
+            for len in [0, 1, CHUNK - 1, CHUNK, CHUNK + 1] {
+                let plain = plaintext(len);
+                assert_eq!(open(&seal(cipher, &plain)).unwrap(), plain, "{} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn flipped_ciphertext_byte_is_rejected() {
+        let mut file = seal(Cipher::Aes256Gcm, &plaintext(CHUNK + 1));
+        let start = payload_start(&file);
+        file[start + 100] ^= 1;
+        assert!(matches!(open(&file), Err(Error::InvalidAuthenticationTag)));
+    }
+
+    #[test]
+    fn truncation_at_chunk_boundary_is_rejected() {
+        let file = seal(Cipher::Aes256Gcm, &plaintext(3 * CHUNK));
+        let start = payload_start(&file);
+        for chunks in [1, 2, 3] {
+            let end = start + chunks * SEALED_CHUNK;
+            assert!(open(&file[..end]).is_err(), "{} chunks", chunks);
+        }
+    }
+
+    #[test]
+    fn swapped_chunks_are_rejected() {
+        let mut file = seal(Cipher::Aes256Gcm, &plaintext(3 * CHUNK));
+        let start = payload_start(&file);
+        let (first, rest) = file[start..].split_at_mut(SEALED_CHUNK);
+        first.swap_with_slice(&mut rest[..SEALED_CHUNK]);
+        assert!(matches!(open(&file), Err(Error::InvalidAuthenticationTag)));
+    }
+
+    #[test]
+    fn tampered_trailer_is_rejected() {
+        let plain = plaintext(CHUNK + 1);
+        let file = seal(Cipher::Aes256Gcm, &plain);
+        let at = file.len() - consts::TRAILER_LEN;
+        let trailer = format::decode_trailer(&file[at..], Path::new("test.safe")).unwrap();
+
+        // A trailer claiming another original length no longer matches the
+        // tag of the last chunk.
+        let mut tampered = file.clone();
+        let forged = Trailer {
+            original_len: trailer.original_len + 1,
+            ..trailer
+        };
+        tampered[at..].copy_from_slice(&format::encode_trailer(&forged));
+        assert!(matches!(open(&tampered), Err(Error::InvalidAuthenticationTag)));
+
+        let mut tampered = file;
+        let at = tampered.len() - 1;
+        tampered[at] ^= 1;
+        assert!(open(&tampered).is_err());
+    }
+
+    #[test]
+    fn seekable_reader_crosses_chunk_boundaries() {
+        let plain = plaintext(3 * CHUNK + 10);
+        let path = std::env::temp_dir().join(format!("safefile-seek-{}.safe", std::process::id()));
+        std::fs::write(&path, seal(Cipher::ChaCha20Poly1305, &plain)).unwrap();
+        let res = SeekableReader::open(&path, KEY);
+        std::fs::remove_file(&path).unwrap();
+        let mut reader = res.unwrap();
+        assert_eq!(reader.len(), plain.len() as u64);
+
+        // Reads straddling each boundary, going forward and back.
+        let mut buf = vec![0u8; 20];
+        for offset in [CHUNK - 10, 3 * CHUNK - 10, 2 * CHUNK - 10, 10] {
+            reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, plain[offset..offset + 20], "offset {}", offset);
+        }
+
+        // Reading to the end from inside the second chunk.
+        let offset = plain.len() - CHUNK - 15;
+        reader.seek(SeekFrom::End(-(CHUNK as i64 + 15))).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, plain[offset..]);
+
+        reader.seek(SeekFrom::Current(-(CHUNK as i64))).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, plain[2 * CHUNK + 10..2 * CHUNK + 30]);
+
+        reader.seek(SeekFrom::Start(plain.len() as u64 + 5)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -34,6 +34,11 @@ pub enum Error {
     },
 
     InvalidAuthenticationTag,
+    WrongKey,
+    WrongPassword,
+    KeyslotNotFound {
+        id: String,
+    },
 
     NotEnoughShares {
         provided: u8,
@@ -42,16 +47,48 @@ pub enum Error {
     SharingFailed {
         details: String,
     },
-    #[allow(unused)]
+    InvalidShareMagic {
+        path: PathBuf,
+    },
+    UnsupportedShareVersion {
+        path: PathBuf,
+        version: u8,
+    },
     ShareCorrupted {
         path: PathBuf,
     },
     ShareChecksumMismatch {
         path: PathBuf,
     },
+    ShareFileMismatch {
+        path: PathBuf,
+    },
+    ShareKeyslotMismatch {
+        path: PathBuf,
+    },
+    DuplicateShare {
+        path: PathBuf,
+    },
     ShareVerificationFailed {
         details: String,
     },
+    InvalidMnemonic {
+        name: String,
+        details: String,
+    },
+    InvalidArmor {
+        path: PathBuf,
+        details: String,
+    },
+    InvalidIdentity {
+        path: PathBuf,
+    },
+    InvalidRecipient {
+        details: String,
+    },
+    NoMatchingIdentity {
+        path: PathBuf,
+    },
     #[allow(unused)]
     OtherShareReconstructionError {
         details: String,
@@ -105,12 +142,30 @@ impl fmt::Display for Error {
             Error::InvalidAuthenticationTag => {
                 write!(f, "Decryption failed: authentication tag mismatch")
             }
+            Error::WrongKey => write!(
+                f,
+                "Decryption failed: wrong key (shares are mismatched or corrupted)"
+            ),
+            Error::WrongPassword => write!(f, "Decryption failed: wrong password"),
+            Error::KeyslotNotFound { id } => {
+                write!(f, "Keyslot '{}' not found in safefile", id)
+            }
 
             Error::NotEnoughShares { provided, required } => write!(
                 f,
                 "Not enough key shares: provided {}, required {}",
                 provided, required
             ),
+            Error::InvalidShareMagic { path } => {
+                write!(f, "'{}' is not a safefile key share", path.display())
+            }
+            Error::UnsupportedShareVersion { path, version } => write!(
+                f,
+                "Unsupported key share version {} in '{}' (current version: {})",
+                version,
+                path.display(),
+                consts::SHARE_VERSION
+            ),
             Error::ShareCorrupted { path } => {
                 write!(f, "Key share '{}' is corrupted", path.display())
             }
@@ -119,10 +174,42 @@ impl fmt::Display for Error {
                 "Key share '{}' failed checksum verification",
                 path.display()
             ),
+            Error::ShareFileMismatch { path } => write!(
+                f,
+                "Key share '{}' belongs to a different safefile",
+                path.display()
+            ),
+            Error::ShareKeyslotMismatch { path } => write!(
+                f,
+                "Key share '{}' belongs to a different keyslot than the other shares",
+                path.display()
+            ),
+            Error::DuplicateShare { path } => write!(
+                f,
+                "Key share '{}' duplicates another provided share",
+                path.display()
+            ),
             Error::SharingFailed { details } => write!(f, "Secret sharing failed: {}", details),
             Error::ShareVerificationFailed { details } => {
                 write!(f, "Key share verification failed: {}", details)
             }
+            Error::InvalidMnemonic { name, details } => {
+                write!(f, "Invalid mnemonic '{}': {}", name, details)
+            }
+            Error::InvalidArmor { path, details } => {
+                write!(f, "Invalid armored text in '{}': {}", path.display(), details)
+            }
+            Error::InvalidIdentity { path } => {
+                write!(f, "'{}' is not a safefile identity", path.display())
+            }
+            Error::InvalidRecipient { details } => {
+                write!(f, "Invalid recipient public key: {}", details)
+            }
+            Error::NoMatchingIdentity { path } => write!(
+                f,
+                "Key share '{}' is sealed to a key none of the given identities hold",
+                path.display()
+            ),
             Error::OtherShareReconstructionError { details } => {
                 write!(f, "Share reconstruction error: {}", details)
             }
@@ -139,9 +226,22 @@ impl std::error::Error for Error {}
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Error::Io {
-            path: None,
-            source: e,
+        // Errors raised inside the streaming adapters come back unwrapped.
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Error::Io {
+                path: None,
+                source: e,
+            },
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io { path: None, source } => source,
+            e => std::io::Error::other(e),
         }
     }
 }
@@ -1,8 +1,9 @@
 //! Utilities for writing and reading the safefile header.
 
-use crate::container::SafeHeader;
+use crate::armor::{self, ArmorKind};
+use crate::container::{Cipher, Compression, Content, Keyslot, LegacySafeHeader, SafeHeader};
 use crate::error::Error;
-use std::{fs::File, io::{Read, Write}, path::{Path, PathBuf}};
+use std::{fs::File, io::{Cursor, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
 use crate::consts;
 
 /// Lightweight inspection information extracted from a safefile header.
@@ -10,33 +11,117 @@ pub struct SafeInfo {
     pub version: u8,
     pub timestamp: u64,
     pub label: String,
-    pub ciphertext_len: u64,
+    /// Identifier shared with the key shares; `None` for legacy files.
+    pub file_id: Option<[u8; 16]>,
+    pub cipher: Cipher,
+    pub compression: Compression,
+    pub content: Content,
+    /// Length of the encrypted payload, after any compression; `None` if
+    /// it is unknown.
+    pub ciphertext_len: Option<u64>,
+    /// Length of the original input; `None` if it is unknown.
+    pub original_len: Option<u64>,
+    pub keyslots: Vec<Keyslot>,
+}
+
+/// Encode the header into its on-disk form: magic, length and encoded header.
+pub fn encode_header(header: &SafeHeader) -> Result<Vec<u8>, Error> {
+    let encoded =
+        bincode::serde::encode_to_vec(header, bincode::config::standard())?;
+
+    let len = encoded.len() as u32;
+
+    let mut out = Vec::with_capacity(8 + encoded.len());
+    out.extend_from_slice(consts::MAGIC);
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&encoded);
+
+    Ok(out)
 }
 
 /// Encode and write the header to `w`.
+///
+/// Returns the exact bytes written.
 pub fn write_header<W: Write>(
     w: &mut W,
     header: &SafeHeader,
-) -> Result<(), Error> {
-    let encoded =
-        bincode::serde::encode_to_vec(header, bincode::config::standard())?;
+) -> Result<Vec<u8>, Error> {
+    let raw = encode_header(header)?;
+    w.write_all(&raw)?;
 
-    let len = encoded.len() as u32;
+    Ok(raw)
+}
+
+/// Associated data binding `header` to the payload.
+///
+/// This is the encoded header with an empty keyslot list, so that keyslots
+/// can change without invalidating the payload.
+pub fn header_aad(header: &SafeHeader) -> Result<Vec<u8>, Error> {
+    let mut bare = header.clone();
+    bare.keyslots.clear();
+    encode_header(&bare)
+}
+
+/// Lengths recorded in the trailer after the last chunk.
+///
+/// - `payload_len` is the number of bytes sealed in the chunks, tags excluded.
+/// - `original_len` is the length of the input, which is larger if it was
+///   compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trailer {
+    pub payload_len: u64,
+    pub original_len: u64,
+}
+
+/// Encode `trailer` into its on-disk form.
+pub fn encode_trailer(trailer: &Trailer) -> [u8; consts::TRAILER_LEN] {
+    let mut out = [0u8; consts::TRAILER_LEN];
+    out[..4].copy_from_slice(consts::TRAILER_MAGIC);
+    out[4..12].copy_from_slice(&trailer.payload_len.to_le_bytes());
+    out[12..].copy_from_slice(&trailer.original_len.to_le_bytes());
+    out
+}
 
-    w.write_all(consts::MAGIC)?;
-    w.write_all(&len.to_le_bytes())?;
-    w.write_all(&encoded)?;
+/// Decode a trailer.
+///
+/// The trailer is only authentic once the last chunk has verified.
+pub fn decode_trailer(raw: &[u8], path: &Path) -> Result<Trailer, Error> {
+    match raw.strip_prefix(consts::TRAILER_MAGIC.as_slice()) {
+        Some(lens) if lens.len() == 16 => Ok(Trailer {
+            payload_len: u64::from_le_bytes(lens[..8].try_into().expect("checked length")),
+            original_len: u64::from_le_bytes(lens[8..].try_into().expect("checked length")),
+        }),
+        _ => Err(Error::InvalidFormat {
+            path: path.to_path_buf(),
+            details: "missing trailer, the file may be truncated".into(),
+        }),
+    }
+}
 
-    Ok(())
+/// Read the trailer at the end of `r`, then seek back to where `r` was.
+pub fn read_trailer<R: Read + Seek + ?Sized>(r: &mut R, path: &Path) -> Result<Trailer, Error> {
+    let pos = r.stream_position()?;
+    let mut raw = [0u8; consts::TRAILER_LEN];
+    let res = r
+        .seek(SeekFrom::End(-(consts::TRAILER_LEN as i64)))
+        .and_then(|_| r.read_exact(&mut raw));
+    r.seek(SeekFrom::Start(pos))?;
+    if res.is_err() {
+        return Err(Error::IncompleteFile {
+            path: path.to_path_buf(),
+        });
+    }
+    decode_trailer(&raw, path)
 }
 
 /// Read and decode the header from `r`.
 ///
-/// Returns the decoded `SafeHeader` and the size of the header.
+/// Returns the decoded `SafeHeader` and the raw header bytes as they
+/// appear in the file (magic + length + encoded header).
 pub fn read_header<R: Read>(
     r: &mut R,
     path: PathBuf,
-) -> Result<(SafeHeader, u32), Error> {
+) -> Result<(SafeHeader, Vec<u8>), Error> {
     let mut magic = [0u8; 4];
     r.read_exact(&mut magic)?;
     if &magic != consts::MAGIC {
@@ -54,39 +139,123 @@ pub fn read_header<R: Read>(
         });
     }
 
-    let mut header_buf = vec![0u8; header_len];
-    r.read_exact(&mut header_buf)?;
-
-    let (header, _): (SafeHeader, usize) =
-        bincode::serde::decode_from_slice(
-            &header_buf,
-            bincode::config::standard(),
-        )?;
-    
-    if header.version != consts::VERSION {
-        return Err(Error::UnsupportedVersion {
-            path,
-            version: header.version,
-        });
+    let mut raw = Vec::with_capacity(8 + header_len);
+    raw.extend_from_slice(&magic);
+    raw.extend_from_slice(&len_buf);
+    raw.resize(8 + header_len, 0);
+    r.read_exact(&mut raw[8..])?;
+
+    // The version is the first encoded field and always a single byte.
+    let version = raw.get(8).copied().unwrap_or(0);
+    let legacy = (consts::MIN_VERSION..=consts::LAST_STREAM_VERSION).contains(&version);
+    if !legacy && version != consts::VERSION {
+        return Err(Error::UnsupportedVersion { path, version });
     }
 
-    Ok((header, header_len as u32))
+    let header: SafeHeader = if legacy {
+        let (legacy, _): (LegacySafeHeader, usize) =
+            bincode::serde::decode_from_slice(
+                &raw[8..],
+                bincode::config::standard(),
+            )?;
+        legacy.into()
+    } else {
+        let (header, _): (SafeHeader, usize) =
+            bincode::serde::decode_from_slice(
+                &raw[8..],
+                bincode::config::standard(),
+            )?;
+        header
+    };
+
+    Ok((header, raw))
 }
 
-/// Open a safefile and return basic header information.
-pub fn inspect_safe_from_path(safe_path: &Path) -> Result<SafeInfo, Error> {
+impl From<SafeHeader> for SafeInfo {
+    fn from(header: SafeHeader) -> Self {
+        SafeInfo {
+            file_id: (!header.is_legacy()).then_some(header.file_id),
+            version: header.version,
+            cipher: header.cipher,
+            compression: header.compression,
+            content: header.content,
+            timestamp: header.timestamp,
+            label: header.label,
+            ciphertext_len: header.ciphertext_len,
+            original_len: match header.compression {
+                Compression::None => header.ciphertext_len,
+                Compression::Zstd => None,
+            },
+            keyslots: header.keyslots,
+        }
+    }
+}
+
+/// Reader that can also seek.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Open a safefile for reading, decoding ASCII armor if present.
+///
+/// Returns a reader positioned at the start of the binary safefile and the
+/// size of the binary safefile in bytes.
+pub fn open_safe(safe_path: &Path) -> Result<(Box<dyn ReadSeek>, u64), Error> {
     let mut f = File::open(safe_path).map_err(|e| Error::Io {
         path: Some(safe_path.to_path_buf()),
         source: e,
     })?;
+    let len = f.metadata()?.len();
+
+    if !is_armored_file(&mut f)? {
+        return Ok((Box::new(f), len));
+    }
 
-    let (header, _hdr_len) = crate::format::read_header(&mut f, safe_path.to_path_buf())?;
+    // Armored text is a third larger than the data, plus line breaks.
+    if len > armor::MAX_ARMOR_LEN * 2 {
+        return Err(Error::InvalidArmor {
+            path: safe_path.to_path_buf(),
+            details: "armored safefile too large".into(),
+        });
+    }
+    let mut text = String::new();
+    f.read_to_string(&mut text)?;
+    let data = armor::decode(&text, ArmorKind::Safefile, safe_path)?;
+    let len = data.len() as u64;
+
+    Ok((Box::new(Cursor::new(data)), len))
+}
+
+/// Whether the file `f` has an armor begin line near its start. The file
+/// is rewound afterwards.
+pub fn is_armored_file(f: &mut File) -> Result<bool, Error> {
+    let mut head = Vec::new();
+    (&mut *f).take(256).read_to_end(&mut head)?;
+    f.seek(SeekFrom::Start(0))?;
+    Ok(!head.starts_with(consts::MAGIC) && armor::is_armored(&head))
+}
+
+/// Open a safefile and read its header.
+pub fn read_header_from_path(safe_path: &Path) -> Result<(SafeHeader, Vec<u8>), Error> {
+    let (mut r, _len) = open_safe(safe_path)?;
 
-    Ok(SafeInfo {
-        version: header.version,
-        timestamp: header.timestamp,
-        label: header.label,
-        ciphertext_len: header.ciphertext_len,
-    })
+    read_header(&mut r, safe_path.to_path_buf())
+}
+
+/// Open a safefile and return basic header information.
+///
+/// The lengths of chunked files are taken from the trailer, unverified.
+pub fn inspect_safe_from_path(safe_path: &Path) -> Result<SafeInfo, Error> {
+    let (mut r, _len) = open_safe(safe_path)?;
+    let (header, _raw) = read_header(&mut r, safe_path.to_path_buf())?;
+    let legacy = header.is_legacy();
+
+    let mut info: SafeInfo = header.into();
+    if !legacy {
+        let trailer = read_trailer(&mut r, safe_path)?;
+        info.ciphertext_len = Some(trailer.payload_len);
+        info.original_len = Some(trailer.original_len);
+    }
+    Ok(info)
 }
 
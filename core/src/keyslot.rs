@@ -0,0 +1,268 @@
+//! Keyslots: wrapping the data key under keys held by the user.
+//!
+//! The wrapped key is authenticated together with the file ID and the
+//! slot's ID and metadata (KDF parameters, threshold, shares): a slot whose
+//! metadata was changed no longer unlocks.
+
+use crate::container::{KdfParams, Keyslot, KeyslotKind};
+use crate::error::Error;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use zeroize::Zeroize;
+
+/// Upper bound on the Argon2id memory cost accepted from a header (4 GiB).
+const MAX_KDF_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+
+/// Derive a 256-bit key from `password` with Argon2id.
+pub fn derive_password_key(
+    password: &[u8],
+    salt: &[u8; 16],
+    kdf: &KdfParams,
+) -> Result<[u8; 32], Error> {
+    if kdf.memory_kib > MAX_KDF_MEMORY_KIB {
+        return Err(Error::InternalError {
+            details: format!("KDF memory cost too large: {} KiB", kdf.memory_kib),
+        });
+    }
+
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(32))
+        .map_err(|e| Error::InternalError {
+            details: format!("invalid KDF parameters: {}", e),
+        })?;
+
+    let mut out = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password, salt, &mut out)
+        .map_err(|e| Error::InternalError {
+            details: format!("key derivation failed: {}", e),
+        })?;
+
+    Ok(out)
+}
+
+/// Associated data of the key wrapped in keyslot `id` of kind `kind`.
+fn slot_aad(file_id: &[u8; 16], id: &[u8; 8], kind: &KeyslotKind) -> Result<Vec<u8>, Error> {
+    let mut aad = [file_id.as_slice(), id].concat();
+    aad.extend(bincode::serde::encode_to_vec(kind, bincode::config::standard())?);
+    Ok(aad)
+}
+
+/// Encrypt `key` under `kek`, binding it to `aad`.
+///
+/// Returns the random nonce and the wrapped key with its tag.
+pub fn wrap_key(
+    kek: &[u8; 32],
+    key: &[u8; 32],
+    aad: &[u8],
+) -> Result<([u8; 12], Vec<u8>), Error> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+
+    let wrapped = Aes256Gcm::new(kek.into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload { msg: key, aad },
+        )
+        .map_err(|_e| Error::InternalError {
+            details: "key wrapping failed".into(),
+        })?;
+
+    Ok((nonce, wrapped))
+}
+
+/// Decrypt a key wrapped by `wrap_key`.
+///
+/// Returns `None` if `kek` is wrong or the wrapped key was modified.
+pub fn unwrap_key(
+    kek: &[u8; 32],
+    nonce: &[u8; 12],
+    wrapped: &[u8],
+    aad: &[u8],
+) -> Option<[u8; 32]> {
+    let mut pt = Aes256Gcm::new(kek.into())
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload { msg: wrapped, aad },
+        )
+        .ok()?;
+
+    let key = <[u8; 32]>::try_from(pt.as_slice()).ok();
+    pt.zeroize();
+    key
+}
+
+/// Create a keyslot wrapping `key` under `kek`.
+fn new_keyslot(
+    kek: &[u8; 32],
+    key: &[u8; 32],
+    file_id: &[u8; 16],
+    kind: KeyslotKind,
+) -> Result<Keyslot, Error> {
+    let mut id = [0u8; 8];
+    OsRng.fill_bytes(&mut id);
+    let (nonce, wrapped_key) = wrap_key(kek, key, &slot_aad(file_id, &id, &kind)?)?;
+
+    Ok(Keyslot {
+        id,
+        nonce,
+        wrapped_key,
+        kind,
+    })
+}
+
+/// Create a password keyslot wrapping `key` with default KDF parameters.
+pub fn password_keyslot(
+    password: &str,
+    key: &[u8; 32],
+    file_id: &[u8; 16],
+) -> Result<Keyslot, Error> {
+    let kdf = KdfParams::default();
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+
+    let mut kek = derive_password_key(password.as_bytes(), &salt, &kdf)?;
+    let slot = new_keyslot(&kek, key, file_id, KeyslotKind::Password { kdf, salt });
+    kek.zeroize();
+    slot
+}
+
+/// Create a Shamir keyslot wrapping `key` under a fresh random slot key.
+///
+/// Returns the keyslot and the slot key, which the caller splits into
+/// shares tagged with the keyslot's `id`.
+pub fn shamir_keyslot(
+    key: &[u8; 32],
+    file_id: &[u8; 16],
+    threshold: u8,
+    shares: u8,
+) -> Result<(Keyslot, [u8; 32]), Error> {
+    let mut slot_key = [0u8; 32];
+    OsRng.fill_bytes(&mut slot_key);
+
+    match new_keyslot(&slot_key, key, file_id, KeyslotKind::Shamir { threshold, shares }) {
+        Ok(slot) => Ok((slot, slot_key)),
+        Err(e) => {
+            slot_key.zeroize();
+            Err(e)
+        }
+    }
+}
+
+/// Recover the data key by trying `password` on every password keyslot.
+pub fn unlock_password(
+    keyslots: &[Keyslot],
+    password: &str,
+    file_id: &[u8; 16],
+) -> Result<[u8; 32], Error> {
+    let mut found = false;
+    for slot in keyslots {
+        let KeyslotKind::Password { kdf, salt } = &slot.kind else {
+            continue;
+        };
+        found = true;
+
+        let aad = slot_aad(file_id, &slot.id, &slot.kind)?;
+        let mut kek = derive_password_key(password.as_bytes(), salt, kdf)?;
+        let key = unwrap_key(&kek, &slot.nonce, &slot.wrapped_key, &aad);
+        kek.zeroize();
+
+        if let Some(key) = key {
+            return Ok(key);
+        }
+    }
+
+    if !found {
+        return Err(Error::InvalidArgument {
+            details: "safefile has no password keyslot".into(),
+        });
+    }
+    Err(Error::WrongPassword)
+}
+
+/// Recover the data key from the Shamir keyslot `slot_id` using its
+/// reconstructed slot key.
+pub fn unlock_shamir(
+    keyslots: &[Keyslot],
+    slot_id: &[u8; 8],
+    slot_key: &[u8; 32],
+    file_id: &[u8; 16],
+) -> Result<[u8; 32], Error> {
+    let slot = keyslots
+        .iter()
+        .find(|s| {
+            &s.id == slot_id
+                && matches!(s.kind, KeyslotKind::Shamir { .. } | KeyslotKind::Embedded { .. })
+        })
+        .ok_or_else(|| Error::KeyslotNotFound {
+            id: hex::encode(slot_id),
+        })?;
+
+    let aad = slot_aad(file_id, &slot.id, &slot.kind)?;
+    unwrap_key(slot_key, &slot.nonce, &slot.wrapped_key, &aad).ok_or(Error::WrongKey)
+}
+
+/// Change the kind of `slot`, re-wrapping its key under `kek` so that the
+/// new metadata is authenticated.
+pub fn set_kind(
+    slot: &mut Keyslot,
+    kek: &[u8; 32],
+    file_id: &[u8; 16],
+    kind: KeyslotKind,
+) -> Result<(), Error> {
+    let aad = slot_aad(file_id, &slot.id, &slot.kind)?;
+    let mut key = unwrap_key(kek, &slot.nonce, &slot.wrapped_key, &aad).ok_or(Error::WrongKey)?;
+    let res = slot_aad(file_id, &slot.id, &kind).and_then(|aad| wrap_key(kek, &key, &aad));
+    key.zeroize();
+
+    (slot.nonce, slot.wrapped_key) = res?;
+    slot.kind = kind;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const FILE_ID: [u8; 16] = [1; 16];
+
+    fn unlock(slot: &Keyslot, slot_key: &[u8; 32], file_id: &[u8; 16]) -> Result<[u8; 32], Error> {
+        unlock_shamir(std::slice::from_ref(slot), &slot.id, slot_key, file_id)
+    }
+
+    #[test]
+    fn shamir_slot_unlocks() {
+        let (slot, slot_key) = shamir_keyslot(&KEY, &FILE_ID, 2, 3).unwrap();
+        assert_eq!(unlock(&slot, &slot_key, &FILE_ID).unwrap(), KEY);
+        assert!(unlock(&slot, &slot_key, &[2; 16]).is_err());
+    }
+
+    #[test]
+    fn changed_metadata_is_rejected() {
+        let (mut slot, slot_key) = shamir_keyslot(&KEY, &FILE_ID, 2, 3).unwrap();
+        slot.kind = KeyslotKind::Shamir {
+            threshold: 1,
+            shares: 3,
+        };
+        assert!(matches!(unlock(&slot, &slot_key, &FILE_ID), Err(Error::WrongKey)));
+    }
+
+    #[test]
+    fn set_kind_rewraps_the_key() {
+        let (mut slot, slot_key) = shamir_keyslot(&KEY, &FILE_ID, 2, 3).unwrap();
+        let kind = KeyslotKind::Embedded {
+            threshold: 2,
+            shares: Vec::new(),
+        };
+        set_kind(&mut slot, &slot_key, &FILE_ID, kind).unwrap();
+        assert_eq!(unlock(&slot, &slot_key, &FILE_ID).unwrap(), KEY);
+
+        let KeyslotKind::Embedded { threshold, .. } = &mut slot.kind else {
+            unreachable!();
+        };
+        *threshold = 1;
+        assert!(unlock(&slot, &slot_key, &FILE_ID).is_err());
+    }
+}
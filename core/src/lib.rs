@@ -1,9 +1,17 @@
 pub mod crypto;
 pub mod shamir;
-pub mod stream_aes;
+pub mod stream;
 pub mod format;
 pub mod container;
 pub mod error;
 pub mod ops;
 pub mod utils;
 pub mod consts;
+pub mod atomic;
+pub mod keyslot;
+pub mod recipient;
+pub mod mnemonic;
+pub mod armor;
+pub mod qr;
+pub mod sheet;
+pub mod archive;
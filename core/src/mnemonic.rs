@@ -0,0 +1,368 @@
+//! Word-list encoding of share files for writing on paper.
+//!
+//! A mnemonic is a list of words from the BIP-39 English word list, each
+//! carrying 11 bits: a format version word, the share data and
+//! `CHECK_WORDS` Reed–Solomon check words over GF(2048). The check words
+//! detect up to four wrong words and locate a single wrong word, so typos
+//! are reported by position before any key reconstruction is attempted.
+
+use crate::consts;
+use crate::container::ShareFile;
+use crate::error::Error;
+use bip39::Language;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+/// Number of Reed–Solomon check words appended to every mnemonic.
+const CHECK_WORDS: usize = 4;
+/// Data bytes are padded to a multiple of this, which is 8 words.
+const BLOCK_BYTES: usize = 11;
+/// Reduction polynomial of GF(2048): x^11 + x^2 + 1.
+const GF_POLY: u16 = 0x805;
+/// Number of non-zero elements of GF(2048), also the maximum code length.
+const GF_ORDER: usize = 2047;
+
+/// Share fields carried by a mnemonic. The checksum is left out since the
+/// check words already protect the data.
+#[derive(Serialize, Deserialize)]
+struct MnemonicShare {
+    timestamp: u64,
+    label: String,
+    file_id: [u8; 16],
+    slot_id: [u8; 8],
+    index: u8,
+    threshold: u8,
+    total: u8,
+    share: Vec<u8>,
+}
+
+/// Encode `sfile` as a list of words.
+pub fn encode(sfile: &ShareFile) -> Result<Vec<&'static str>, Error> {
+    let mut ms = MnemonicShare {
+        timestamp: sfile.timestamp,
+        label: sfile.label.clone(),
+        file_id: sfile.file_id,
+        slot_id: sfile.slot_id,
+        index: sfile.index,
+        threshold: sfile.threshold,
+        total: sfile.total,
+        share: sfile.share.clone(),
+    };
+    let data = bincode::serde::encode_to_vec(&ms, bincode::config::standard());
+    ms.share.zeroize();
+    let mut data = data?;
+
+    // ISO/IEC 7816-4 padding: 0x80 followed by zeros.
+    data.push(0x80);
+    while !data.len().is_multiple_of(BLOCK_BYTES) {
+        data.push(0);
+    }
+
+    let mut symbols = vec![consts::MNEMONIC_VERSION as u16];
+    symbols.extend(bytes_to_symbols(&data));
+    data.zeroize();
+
+    if symbols.len() + CHECK_WORDS > GF_ORDER {
+        symbols.zeroize();
+        return Err(Error::InvalidArgument {
+            details: "share is too long for a mnemonic, use a shorter label".into(),
+        });
+    }
+
+    let gf = Gf::new();
+    let check = gf.check_symbols(&symbols);
+    symbols.extend_from_slice(&check);
+
+    let list = Language::English.word_list();
+    let words = symbols.iter().map(|&s| list[s as usize]).collect();
+    symbols.zeroize();
+    Ok(words)
+}
+
+/// Whether `raw` looks like a mnemonic rather than a binary share file.
+pub fn is_mnemonic(raw: &[u8]) -> bool {
+    std::str::from_utf8(raw).is_ok_and(|text| {
+        tokens(text)
+            .next()
+            .is_some_and(|t| t.chars().all(|c| c.is_ascii_alphabetic()))
+    })
+}
+
+/// Decode a mnemonic, reporting wrong words by position.
+///
+/// Words may be abbreviated to any unique prefix of at least four letters,
+/// and numbers used to count the words are ignored. `name` identifies the
+/// mnemonic in errors.
+pub fn decode(text: &str, name: &str) -> Result<ShareFile, Error> {
+    let invalid = |details: String| Error::InvalidMnemonic {
+        name: name.to_string(),
+        details,
+    };
+
+    let words: Vec<String> = tokens(text).map(|t| t.to_lowercase()).collect();
+    let mut symbols: Vec<u16> = Vec::with_capacity(words.len());
+    let mut unknown = Vec::new();
+    for (i, w) in words.iter().enumerate() {
+        match lookup(w) {
+            Some(s) => symbols.push(s),
+            None => {
+                unknown.push(i);
+                symbols.push(0);
+            }
+        }
+    }
+
+    if symbols.len() < 1 + CHECK_WORDS + BLOCK_BYTES * 8 / 11 || symbols.len() > GF_ORDER {
+        return Err(invalid(format!("wrong number of words ({})", symbols.len())));
+    }
+
+    let gf = Gf::new();
+    let syndromes = gf.syndromes(&symbols);
+    if !unknown.is_empty() {
+        let listed: Vec<String> = unknown
+            .iter()
+            .map(|&i| format!("{} '{}'", i + 1, words[i]))
+            .collect();
+        let mut details = if let [only] = &listed[..] {
+            format!("word {} is not in the word list", only)
+        } else {
+            format!("words {} are not in the word list", listed.join(", "))
+        };
+        if let [i] = unknown[..]
+            && let Some(value) = gf.locate(&syndromes, symbols.len())
+                .filter(|(pos, _)| *pos == i)
+                .map(|(_, e)| e)
+        {
+            details += &format!(", expected '{}'", word(value));
+        }
+        symbols.zeroize();
+        return Err(invalid(details));
+    }
+
+    if syndromes.iter().any(|&s| s != 0) {
+        let details = match gf.locate(&syndromes, symbols.len()) {
+            Some((pos, e)) => format!(
+                "word {} '{}' is wrong, expected '{}'",
+                pos + 1,
+                words[pos],
+                word(symbols[pos] ^ e)
+            ),
+            None => "checksum mismatch, more than one word is wrong".into(),
+        };
+        symbols.zeroize();
+        return Err(invalid(details));
+    }
+
+    let version = symbols[0];
+    if version != consts::MNEMONIC_VERSION as u16 {
+        symbols.zeroize();
+        return Err(invalid(format!("unsupported mnemonic version {}", version)));
+    }
+
+    let data_words = &symbols[1..symbols.len() - CHECK_WORDS];
+    if !data_words.len().is_multiple_of(8) {
+        symbols.zeroize();
+        return Err(invalid(format!("wrong number of words ({})", words.len())));
+    }
+    let mut data = symbols_to_bytes(data_words);
+    symbols.zeroize();
+
+    let res = unpad(&data)
+        .and_then(|unpadded| {
+            match bincode::serde::decode_from_slice::<MnemonicShare, _>(
+                unpadded,
+                bincode::config::standard(),
+            ) {
+                Ok((ms, used)) if used == unpadded.len() => Some(ms),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| invalid("malformed share data".into()));
+    data.zeroize();
+    let ms = res?;
+
+    let mut checksum = [0u8; 32];
+    checksum.copy_from_slice(&Sha256::digest(&ms.share));
+
+    Ok(ShareFile {
+        version: consts::SHARE_VERSION,
+        timestamp: ms.timestamp,
+        label: ms.label,
+        file_id: ms.file_id,
+        slot_id: ms.slot_id,
+        index: ms.index,
+        threshold: ms.threshold,
+        total: ms.total,
+        share: ms.share,
+        checksum,
+    })
+}
+
+/// Whitespace separated words of `text`, skipping word numbers such as
+/// `12.` or `12:`.
+fn tokens(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace().filter(|t| {
+        !t.trim_end_matches(['.', ':', ')'])
+            .chars()
+            .all(|c| c.is_ascii_digit())
+    })
+}
+
+/// Index of `w` in the word list, also accepting unique prefixes of at
+/// least four letters.
+fn lookup(w: &str) -> Option<u16> {
+    let lang = Language::English;
+    lang.find_word(w).or_else(|| match lang.words_by_prefix(w) {
+        [only] if w.len() >= 4 => lang.find_word(only),
+        _ => None,
+    })
+}
+
+fn word(symbol: u16) -> &'static str {
+    Language::English.word_list()[symbol as usize]
+}
+
+/// Split bytes into 11-bit symbols; the length must be a multiple of 11.
+fn bytes_to_symbols(bytes: &[u8]) -> Vec<u16> {
+    let mut out = Vec::with_capacity(bytes.len() * 8 / 11);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &b in bytes {
+        acc = (acc << 8) | b as u32;
+        bits += 8;
+        if bits >= 11 {
+            bits -= 11;
+            out.push(((acc >> bits) & 0x7ff) as u16);
+        }
+    }
+    out
+}
+
+/// Join 11-bit symbols into bytes; the count must be a multiple of 8.
+fn symbols_to_bytes(symbols: &[u16]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(symbols.len() * 11 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &s in symbols {
+        acc = (acc << 11) | s as u32;
+        bits += 11;
+        while bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    out
+}
+
+/// Strip the padding added by `encode`.
+fn unpad(data: &[u8]) -> Option<&[u8]> {
+    let end = data.iter().rposition(|&b| b != 0)?;
+    (data[end] == 0x80).then_some(&data[..end])
+}
+
+/// Arithmetic in GF(2048) and the Reed–Solomon code built on it.
+///
+/// A codeword `c_0 .. c_{n-1}` is read as the polynomial
+/// `c_0 x^{n-1} + ... + c_{n-1}`, which is a multiple of
+/// `(x - α)(x - α^2)...(x - α^CHECK_WORDS)`.
+struct Gf {
+    exp: [u16; 2 * GF_ORDER],
+    log: [u16; GF_ORDER + 1],
+}
+
+impl Gf {
+    fn new() -> Self {
+        let mut gf = Gf {
+            exp: [0; 2 * GF_ORDER],
+            log: [0; GF_ORDER + 1],
+        };
+        let mut x: u16 = 1;
+        for i in 0..GF_ORDER {
+            gf.exp[i] = x;
+            gf.exp[i + GF_ORDER] = x;
+            gf.log[x as usize] = i as u16;
+            x <<= 1;
+            if x & 0x800 != 0 {
+                x ^= GF_POLY;
+            }
+        }
+        gf
+    }
+
+    fn mul(&self, a: u16, b: u16) -> u16 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    fn div(&self, a: u16, b: u16) -> u16 {
+        if a == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + GF_ORDER - self.log[b as usize] as usize]
+    }
+
+    /// Check symbols to append to `msg`: the remainder of
+    /// `msg(x) * x^CHECK_WORDS` divided by the generator polynomial.
+    fn check_symbols(&self, msg: &[u16]) -> [u16; CHECK_WORDS] {
+        // Generator coefficients, highest degree first.
+        let mut generator = vec![1u16];
+        for j in 1..=CHECK_WORDS {
+            let root = self.exp[j];
+            let mut next = generator.clone();
+            next.push(0);
+            for (i, &g) in generator.iter().enumerate() {
+                next[i + 1] ^= self.mul(g, root);
+            }
+            generator = next;
+        }
+
+        let mut rem = [0u16; CHECK_WORDS];
+        for &m in msg {
+            let factor = m ^ rem[0];
+            for i in 0..CHECK_WORDS - 1 {
+                rem[i] = rem[i + 1] ^ self.mul(factor, generator[i + 1]);
+            }
+            rem[CHECK_WORDS - 1] = self.mul(factor, generator[CHECK_WORDS]);
+        }
+        rem
+    }
+
+    /// Evaluate the codeword at `α^1 .. α^CHECK_WORDS`; all zero if valid.
+    fn syndromes(&self, code: &[u16]) -> [u16; CHECK_WORDS] {
+        let mut out = [0u16; CHECK_WORDS];
+        for (j, s) in out.iter_mut().enumerate() {
+            let root = self.exp[j + 1];
+            *s = code.iter().fold(0, |acc, &c| self.mul(acc, root) ^ c);
+        }
+        out
+    }
+
+    /// Locate a single wrong symbol in a codeword of length `n`.
+    ///
+    /// Returns its position and the value to XOR into it, or `None` if the
+    /// syndromes do not match exactly one wrong symbol.
+    fn locate(&self, syndromes: &[u16; CHECK_WORDS], n: usize) -> Option<(usize, u16)> {
+        if syndromes[0] == 0 {
+            return None;
+        }
+        // A single error e at power k gives S_j = e * α^(jk).
+        let step = self.div(syndromes[1], syndromes[0]);
+        if step == 0 {
+            return None;
+        }
+        for j in 1..CHECK_WORDS - 1 {
+            if syndromes[j + 1] != self.mul(syndromes[j], step) {
+                return None;
+            }
+        }
+
+        let k = self.log[step as usize] as usize;
+        if k >= n {
+            return None;
+        }
+        Some((n - 1 - k, self.div(syndromes[0], step)))
+    }
+}
@@ -1,16 +1,25 @@
 //! High-level operations that compose encryption and secret sharing.
 
+use crate::armor::{self, ArmorKind};
+use crate::atomic::{AtomicDir, AtomicFile};
 use crate::consts;
-use crate::container::SafeHeader;
-use crate::crypto;
+use crate::container::{Compression, Content, Keyslot, KeyslotKind, SafeHeader};
+use crate::archive::{self, IndexEntry};
+use crate::crypto::{self, CryptoOptions, SeekableReader};
 use crate::error::Error;
 use crate::format;
 use crate::format::SafeInfo;
-use crate::shamir;
+use crate::keyslot;
+use crate::qr::ShareQr;
+use crate::recipient::{Identity, Recipient};
+use crate::shamir::{self, ShareMeta};
+use crate::sheet;
 use crate::utils;
 use aes_gcm::aead::OsRng;
 use aes_gcm::aead::rand_core::RngCore;
-use std::path::Path;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use zeroize::Zeroize;
 
@@ -26,18 +35,77 @@ pub struct DecryptResult {
     pub info: SafeInfo,
 }
 
+pub struct KeyslotResult {
+    pub slot_id: [u8; 8],
+    pub share_files: Vec<String>,
+}
+
+/// Existing way of unlocking a safefile.
+pub enum Unlock<'a> {
+    /// Share files, sealed ones opened with `identities`.
+    Shares {
+        paths: &'a [&'a Path],
+        identities: &'a [Identity],
+    },
+    /// Typed share mnemonics, see `mnemonic::decode`.
+    Mnemonics(&'a [&'a str]),
+    Password(&'a str),
+}
+
+/// Plaintext to encrypt.
+pub enum Input<'a> {
+    File(&'a Path),
+    /// A stream of unknown length, such as stdin.
+    Stream(&'a mut dyn Read),
+    /// A directory tree, stored as an archive (see `archive`).
+    Directory(&'a Path),
+}
+
+/// Where decrypted plaintext goes.
+pub enum Output<'a> {
+    /// Staged and only moved into place once the whole file authenticated.
+    /// Archives are unpacked into a new directory at this path instead.
+    File(&'a Path),
+    /// Written chunk by chunk as each verifies, such as stdout. On error the
+    /// stream has received partial data and must be discarded. Archives are
+    /// written as their tar stream.
+    Stream(&'a mut dyn Write),
+}
+
+/// How to split a slot key into shares.
+///
+/// - `outdir` is the directory the share files are written to; if `None`,
+///   the shares are sealed to `recipients` and embedded in the header.
+/// - `shares` is the number of shares, `threshold` how many are needed.
+/// - `recipients` are the holders the shares are sealed to, one per share;
+///   plain share files are written if empty.
+pub struct ShareOptions<'a> {
+    pub outdir: Option<&'a Path>,
+    pub shares: u8,
+    pub threshold: u8,
+    pub recipients: &'a [Recipient],
+}
+
+/// Keyslot to add to a safefile.
+pub enum NewKeyslot<'a> {
+    /// Split a new slot key into share files.
+    Shares(ShareOptions<'a>),
+    Password(&'a str),
+}
+
 /// Does the whole process of encyprion and splitting.
 /// 
 /// - Generates a random 256-bit key
-/// - Stream-encrypts an input file with AES-256-GCM
-/// - Splits the key using SSS and writes shares to disk
+/// - Encrypts an input file with the chosen AEAD in authenticated chunks
+/// - Wraps the key in a keyslot whose key is split using SSS and writes
+///   the shares to disk, sealed to their holders if recipients are given,
+///   or embeds the sealed shares in the header if there is no `outdir`
 pub fn encrypt_and_split<F>(
-    input: &Path,
+    input: Input,
     output: &Path,
-    outdir: &Path,
-    shares: u8,
-    threshold: u8,
+    split: &ShareOptions,
     label: Option<&str>,
+    options: &CryptoOptions,
     progress_callback: F,
 ) -> Result<EncryptResult, Error>
 where
@@ -46,73 +114,149 @@ where
     let mut io_timer = utils::Timer::new();
     let mut crypto_timer = utils::Timer::new();
 
-    io_timer.start();
-    let metadata = std::fs::metadata(input).map_err(|e| Error::Io {
-        path: Some(input.to_path_buf()),
-        source: e,
-    })?;
-    let pt_len = metadata.len();
-    io_timer.stop();
-
     crypto_timer.start();
-
-    let mut key = [0u8; 32];
-    OsRng.fill_bytes(&mut key);
-    let mut nonce = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce);
+    let mut secrets = FileSecrets::generate();
     crypto_timer.stop();
 
-    let ts = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .map_err(|e| Error::InternalError {
-            details: format!("time error: {}", e),
-        })?
-        .as_secs();
-    let lab = label.unwrap_or("").to_string();
-    let header = SafeHeader {
-        version: consts::VERSION,
-        timestamp: ts,
-        label: lab.clone(),
-        nonce,
-        ciphertext_len: pt_len,
+    let (slot, mut slot_key) =
+        match keyslot::shamir_keyslot(&secrets.key, &secrets.file_id, split.threshold, split.shares) {
+            Ok(s) => s,
+            Err(e) => {
+                secrets.zeroize();
+                return Err(e);
+            }
+        };
+    let slot_id = slot.id;
+    let mut header = new_header(&secrets, label, options, vec![slot])?;
+    let lab = header.label.clone();
+    let meta = ShareMeta {
+        label: &lab,
+        timestamp: header.timestamp,
+        file_id: secrets.file_id,
+        slot_id,
     };
+    if let Err(e) = embed_shares(&mut header.keyslots[0], &slot_key, split, &meta) {
+        secrets.zeroize();
+        slot_key.zeroize();
+        return Err(e);
+    }
 
+    // The safefile and the shares are staged and only moved into place
+    // once all of them are written; dropping them on error removes them.
     io_timer.start();
-    crypto::encrypt_stream(input, output, key, nonce, header, progress_callback)?;
+    let mut staged = AtomicFile::create(output)?;
+    let res = encrypt_input(
+        input,
+        &mut staged,
+        secrets.key,
+        header,
+        options,
+        progress_callback,
+    );
     io_timer.stop();
 
     crypto_timer.start();
-    let share_paths = shamir::create_shares(&key, threshold, shares, outdir, &lab, ts)?;
+    let staged_shares = res.and_then(|_| {
+        split
+            .outdir
+            .map(|outdir| {
+                shamir::create_shares(
+                    &slot_key,
+                    split.threshold,
+                    split.shares,
+                    outdir,
+                    &meta,
+                    split.recipients,
+                )
+            })
+            .transpose()
+    });
 
-    // Zero the key and nonce
-    key.zeroize();
-    nonce.zeroize();
+    // Zero the keys and nonce
+    secrets.zeroize();
+    slot_key.zeroize();
     crypto_timer.stop();
 
+    // Shares go first: a safefile without its shares could not be opened.
+    let share_paths = match staged_shares? {
+        Some(shares) => shares.commit()?,
+        None => Vec::new(),
+    };
+    staged.commit()?;
+
     let info = format::inspect_safe_from_path(output)?;
 
     Ok(EncryptResult {
         safe_file: output.to_string_lossy().into_owned(),
         share_files: share_paths,
-        info: format::SafeInfo {
-            version: info.version,
-            timestamp: info.timestamp,
-            label: info.label,
-            ciphertext_len: info.ciphertext_len,
-        },
+        info,
     })
 }
 
+/// Encrypts a file under a password instead of splitting the key.
+///
+/// - Generates a random 256-bit key
+/// - Wraps it in a keyslot under an Argon2id-derived key
+/// - Encrypts an input file with the chosen AEAD in authenticated chunks
+pub fn encrypt_with_password<F>(
+    input: Input,
+    output: &Path,
+    password: &str,
+    label: Option<&str>,
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<EncryptResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let mut secrets = FileSecrets::generate();
+    let header = keyslot::password_keyslot(password, &secrets.key, &secrets.file_id)
+        .and_then(|slot| new_header(&secrets, label, options, vec![slot]));
+    let header = match header {
+        Ok(h) => h,
+        Err(e) => {
+            secrets.zeroize();
+            return Err(e);
+        }
+    };
+
+    let mut staged = AtomicFile::create(output)?;
+    let res = encrypt_input(
+        input,
+        &mut staged,
+        secrets.key,
+        header,
+        options,
+        progress_callback,
+    );
+
+    secrets.zeroize();
+
+    res?;
+    staged.commit()?;
+
+    let info = format::inspect_safe_from_path(output)?;
+
+    Ok(EncryptResult {
+        safe_file: output.to_string_lossy().into_owned(),
+        share_files: Vec::new(),
+        info,
+    })
+}
 
 /// Does the whole process of recunstruction and decryption.
 /// 
-/// - Reads key shares
-/// - Tries to reconstruct the key
+/// - Reads key shares, opening sealed ones with `identities`, and checks
+///   they belong to the safefile; without share files, opens the shares
+///   embedded in the header instead
+/// - Tries to reconstruct the key and verifies it against the header
 /// - Stream-decrypts the safefile
 pub fn decrypt_and_reconstruct<F>(
     safe_path: &Path,
-    output: &Path,
+    output: Output,
     share_paths: &[&Path],
+    identities: &[Identity],
+    options: &CryptoOptions,
     progress_callback: F,
 ) -> Result<DecryptResult, Error>
 where
@@ -121,31 +265,719 @@ where
     let mut timer = utils::Timer::new();
     timer.start();
 
-    let mut key_vec = shamir::reconstruct_key(share_paths)?;
-    let mut key = [0u8; 32];
+    let (header, _raw) = format::read_header_from_path(safe_path)?;
+    let unlock = Unlock::Shares {
+        paths: share_paths,
+        identities,
+    };
+    let key = unlock_key(safe_path, &header, &unlock)?;
+
+    decrypt_with_key(safe_path, &header, output, key, options, progress_callback)
+}
+
+/// Decrypts a password-protected safefile.
+///
+/// - Derives the keyslot key from the password and unwraps the data key
+/// - Stream-decrypts the safefile
+pub fn decrypt_with_password<F>(
+    safe_path: &Path,
+    output: Output,
+    password: &str,
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<DecryptResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let (header, _raw) = format::read_header_from_path(safe_path)?;
+    let key = unlock_key(safe_path, &header, &Unlock::Password(password))?;
+
+    decrypt_with_key(safe_path, &header, output, key, options, progress_callback)
+}
+
+/// Decrypts a safefile with typed share mnemonics.
+///
+/// - Decodes the mnemonics, reporting mistyped words by position
+/// - Reconstructs the key and verifies it against the header
+/// - Stream-decrypts the safefile
+pub fn decrypt_with_mnemonics<F>(
+    safe_path: &Path,
+    output: Output,
+    mnemonics: &[&str],
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<DecryptResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let (header, _raw) = format::read_header_from_path(safe_path)?;
+    let key = unlock_key(safe_path, &header, &Unlock::Mnemonics(mnemonics))?;
+
+    decrypt_with_key(safe_path, &header, output, key, options, progress_callback)
+}
+
+/// Decrypts `len` bytes of a safefile starting at `start`.
+///
+/// - Unlocks the data key
+/// - Decrypts only the chunks covering the range (see
+///   `crypto::SeekableReader`)
+///
+/// The range is cut short at the end of the plaintext; returns the number
+/// of bytes written.
+pub fn decrypt_range<F>(
+    safe_path: &Path,
+    unlock: &Unlock,
+    start: u64,
+    len: u64,
+    output: Output,
+    mut progress_callback: F,
+) -> Result<u64, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let (header, _raw) = format::read_header_from_path(safe_path)?;
+    let mut key = unlock_key(safe_path, &header, unlock)?;
+    let reader = SeekableReader::open(safe_path, key);
+    key.zeroize();
+    let mut reader = reader?;
+
+    if start > reader.len() {
+        return Err(Error::InvalidArgument {
+            details: format!(
+                "range starts past the end of the plaintext ({} bytes)",
+                reader.len()
+            ),
+        });
+    }
+    let len = len.min(reader.len() - start);
+
+    match output {
+        Output::File(path) => {
+            let mut staged = AtomicFile::create(path)?;
+            reader.copy_range(start, len, &mut staged, &mut progress_callback)?;
+            staged.commit()?;
+        }
+        Output::Stream(writer) => {
+            reader.copy_range(start, len, writer, &mut progress_callback)?;
+            writer.flush()?;
+        }
+    }
+    Ok(len)
+}
+
+/// Lists the entries of an archive safefile.
+///
+/// - Unlocks the data key
+/// - Decrypts the index at the end of the archive, or the whole archive if
+///   it is compressed
+pub fn list_archive(
+    safe_path: &Path,
+    unlock: &Unlock,
+    options: &CryptoOptions,
+) -> Result<Vec<IndexEntry>, Error> {
+    let (header, _raw) = format::read_header_from_path(safe_path)?;
+    let mut key = unlock_key(safe_path, &header, unlock)?;
+
+    let res = crypto::list_tree(safe_path, key, options);
+    key.zeroize();
+    res
+}
+
+/// Extracts one file from an archive safefile.
+///
+/// - Unlocks the data key
+/// - Finds the file in the index and decrypts only the chunks holding it,
+///   unless the archive is compressed
+/// - Restores its permissions and modification time when writing a file
+pub fn extract_entry<F>(
+    safe_path: &Path,
+    unlock: &Unlock,
+    name: &str,
+    output: Output,
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<IndexEntry, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let (header, _raw) = format::read_header_from_path(safe_path)?;
+    let mut key = unlock_key(safe_path, &header, unlock)?;
+
+    match output {
+        Output::File(path) => {
+            let mut staged = AtomicFile::create(path)?;
+            let res = crypto::extract_from_tree(safe_path, name, &mut staged, key, options, progress_callback);
+            key.zeroize();
+
+            let entry = res?;
+            archive::restore_metadata(staged.path(), &entry)?;
+            staged.commit()?;
+            Ok(entry)
+        }
+        Output::Stream(writer) => {
+            let res = crypto::extract_from_tree(safe_path, name, writer, key, options, progress_callback);
+            key.zeroize();
+            res
+        }
+    }
+}
+
+/// Adds a keyslot to an existing safefile.
+///
+/// - Unlocks the data key with an existing method
+/// - Wraps it in the new keyslot, writing shares for Shamir slots
+/// - Rewrites the header; the payload is copied, not re-encrypted
+pub fn add_keyslot(
+    safe_path: &Path,
+    unlock: &Unlock,
+    new: &NewKeyslot,
+) -> Result<KeyslotResult, Error> {
+    let (mut header, raw) = format::read_header_from_path(safe_path)?;
+    if header.is_legacy() {
+        return Err(Error::InvalidArgument {
+            details: "legacy safefiles have no keyslots, re-encrypt the file first".into(),
+        });
+    }
+
+    let mut key = unlock_key(safe_path, &header, unlock)?;
+
+    let (slot, mut slot_key) = match new {
+        NewKeyslot::Password(password) => (
+            keyslot::password_keyslot(password, &key, &header.file_id),
+            None,
+        ),
+        NewKeyslot::Shares(split) => match keyslot::shamir_keyslot(
+            &key,
+            &header.file_id,
+            split.threshold,
+            split.shares,
+        ) {
+            Ok((slot, slot_key)) => (Ok(slot), Some(slot_key)),
+            Err(e) => (Err(e), None),
+        },
+    };
+    key.zeroize();
+
+    let res = slot.and_then(|mut slot| {
+        let slot_id = slot.id;
+        let meta = ShareMeta {
+            label: &header.label,
+            timestamp: header.timestamp,
+            file_id: header.file_id,
+            slot_id,
+        };
+        let split = match (new, &slot_key) {
+            (NewKeyslot::Shares(split), Some(slot_key)) => Some((split, slot_key)),
+            _ => None,
+        };
+        if let Some((split, slot_key)) = split {
+            embed_shares(&mut slot, slot_key, split, &meta)?;
+        }
+        header.keyslots.push(slot);
+
+        // Shares are only written once the new header is staged, and the
+        // header is only committed once the shares are on disk.
+        let staged = stage_with_header(safe_path, &header, raw.len() as u64)?;
+        let share_files = if let Some((split, slot_key)) = split
+            && let Some(outdir) = split.outdir
+        {
+            shamir::create_shares(
+                slot_key,
+                split.threshold,
+                split.shares,
+                outdir,
+                &meta,
+                split.recipients,
+            )?
+            .commit()?
+        } else {
+            Vec::new()
+        };
+        staged.commit()?;
+
+        Ok(KeyslotResult {
+            slot_id,
+            share_files,
+        })
+    });
+
+    if let Some(k) = slot_key.as_mut() {
+        k.zeroize();
+    }
+    res
+}
+
+/// Writes an ASCII-armored copy of a small safefile.
+pub fn armor_safe(safe_path: &Path, output: &Path) -> Result<(), Error> {
+    let (header, _raw) = format::read_header_from_path(safe_path)?;
+    let (mut src, len) = format::open_safe(safe_path)?;
+    if len > armor::MAX_ARMOR_LEN {
+        return Err(Error::InvalidArgument {
+            details: format!(
+                "safefile too large to armor ({} bytes, at most {})",
+                len,
+                armor::MAX_ARMOR_LEN
+            ),
+        });
+    }
+
+    let mut data = Vec::with_capacity(len as usize);
+    src.read_to_end(&mut data)?;
+
+    let mut headers = Vec::new();
+    if !header.label.is_empty() {
+        headers.push(("Label", header.label.clone()));
+    }
+    if !header.is_legacy() {
+        headers.push(("File-ID", hex::encode(header.file_id)));
+    }
+
+    let mut staged = AtomicFile::create(output)?;
+    staged.write_all(armor::encode(ArmorKind::Safefile, &headers, &data).as_bytes())?;
+    staged.commit()
+}
+
+/// Writes the binary form of an ASCII-armored safefile.
+pub fn dearmor_safe(safe_path: &Path, output: &Path) -> Result<(), Error> {
+    format::read_header_from_path(safe_path)?;
+    let (mut src, _len) = format::open_safe(safe_path)?;
+
+    let mut staged = AtomicFile::create(output)?;
+    std::io::copy(&mut src, &mut staged)?;
+    staged.commit()
+}
+
+/// Writes a share file as a QR code image.
+///
+/// The image format follows the output extension, `.png` or `.svg`.
+pub fn share_qr(share_path: &Path, output: &Path) -> Result<(), Error> {
+    let ext = output
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let export = shamir::export_share(share_path)?;
+    let code = ShareQr::new(&export, share_path)?;
+    let image = match ext.as_deref() {
+        Some("png") => code.to_png()?,
+        Some("svg") => code.to_svg().into_bytes(),
+        _ => {
+            return Err(Error::InvalidArgument {
+                details: format!("'{}' must end in .png or .svg", output.display()),
+            });
+        }
+    };
+
+    let mut staged = AtomicFile::create(output)?;
+    staged.write_all(&image)?;
+    staged.commit()
+}
+
+/// Writes a printable HTML sheet for each share file into `outdir`.
+///
+/// Holders are matched to shares in order; a share without one gets a
+/// blank line for the name. Sealed shares are opened with `identities`.
+pub fn write_share_sheets(
+    share_paths: &[&Path],
+    holders: &[&str],
+    identities: &[Identity],
+    outdir: &Path,
+) -> Result<Vec<PathBuf>, Error> {
+    if holders.len() > share_paths.len() {
+        return Err(Error::InvalidArgument {
+            details: format!(
+                "{} holders given for {} shares",
+                holders.len(),
+                share_paths.len()
+            ),
+        });
+    }
+
+    let outputs: Vec<PathBuf> = share_paths
+        .iter()
+        .map(|p| outdir.join(p.file_stem().unwrap_or(p.as_os_str())).with_extension("html"))
+        .collect();
+    for (i, output) in outputs.iter().enumerate() {
+        if outputs[..i].contains(output) {
+            return Err(Error::InvalidArgument {
+                details: format!("two shares would both be written to '{}'", output.display()),
+            });
+        }
+    }
+
+    let mut written = Vec::with_capacity(share_paths.len());
+    for ((i, path), output) in share_paths.iter().enumerate().zip(outputs) {
+        let mut sfile = shamir::read_share(path, identities)?;
+        let html = sheet::render(&sfile, holders.get(i).copied(), path);
+        sfile.share.zeroize();
+        let mut html = html?;
+
+        let mut staged = AtomicFile::create(&output)?;
+        let res = staged.write_all(html.as_bytes());
+        html.zeroize();
+        res?;
+        staged.commit()?;
+        written.push(output);
+    }
+    Ok(written)
+}
+
+/// Removes a keyslot from a safefile.
+///
+/// The data key must be unlocked with an existing method first, and the
+/// last keyslot cannot be removed.
+pub fn remove_keyslot(
+    safe_path: &Path,
+    unlock: &Unlock,
+    slot_id: &[u8; 8],
+) -> Result<(), Error> {
+    let (mut header, raw) = format::read_header_from_path(safe_path)?;
+
+    let mut key = unlock_key(safe_path, &header, unlock)?;
+    key.zeroize();
+
+    let pos = header
+        .keyslots
+        .iter()
+        .position(|s| &s.id == slot_id)
+        .ok_or_else(|| Error::KeyslotNotFound {
+            id: hex::encode(slot_id),
+        })?;
+    if header.keyslots.len() == 1 {
+        return Err(Error::InvalidArgument {
+            details: "cannot remove the last keyslot".into(),
+        });
+    }
+    header.keyslots.remove(pos);
+
+    stage_with_header(safe_path, &header, raw.len() as u64)?.commit()
+}
+
+/// Recover the data key and verify it against the header's key check.
+fn unlock_key(safe_path: &Path, header: &SafeHeader, unlock: &Unlock) -> Result<[u8; 32], Error> {
+    let (mut key_vec, slot_id) = match unlock {
+        Unlock::Password(password) => {
+            let key = keyslot::unlock_password(&header.keyslots, password, &header.file_id)?;
+            return verified(header, key);
+        }
+        Unlock::Shares { paths, identities } if paths.is_empty() && !header.is_legacy() => {
+            reconstruct_embedded(safe_path, header, identities)?
+        }
+        Unlock::Shares { paths, identities } => {
+            shamir::reconstruct_key(paths, identities, share_file_id(header)?)?
+        }
+        Unlock::Mnemonics(texts) => {
+            shamir::reconstruct_from_mnemonics(texts, share_file_id(header)?)?
+        }
+    };
+
+    let mut secret = [0u8; 32];
     if key_vec.len() != 32 {
+        key_vec.zeroize();
         return Err(Error::InternalError {
             details: "reconstructed key wrong length".into(),
         });
     }
-    key.copy_from_slice(&key_vec);
+    secret.copy_from_slice(&key_vec);
 
     key_vec.zeroize();
 
-    crypto::decrypt_stream(safe_path, output, key, progress_callback)?;
+    // Legacy shares split the data key itself.
+    let key = if header.is_legacy() {
+        secret
+    } else {
+        let key = keyslot::unlock_shamir(&header.keyslots, &slot_id, &secret, &header.file_id);
+        secret.zeroize();
+        key?
+    };
+
+    verified(header, key)
+}
 
-    // Zero key after decryption
-    key.zeroize();
+/// Reject a wrong key before any output is created.
+fn verified(header: &SafeHeader, mut key: [u8; 32]) -> Result<[u8; 32], Error> {
+    if let Err(e) = crypto::verify_key(header, &key) {
+        key.zeroize();
+        return Err(e);
+    }
+
+    Ok(key)
+}
+
+/// The `file_id` loose shares must carry, after checking that the
+/// safefile has a keyslot they can unlock.
+fn share_file_id(header: &SafeHeader) -> Result<Option<&[u8; 16]>, Error> {
+    if header.is_legacy() {
+        return Ok(None);
+    }
+
+    let has_shamir = header
+        .keyslots
+        .iter()
+        .any(|s| matches!(s.kind, KeyslotKind::Shamir { .. }));
+    if !has_shamir {
+        return Err(Error::InvalidArgument {
+            details: "safefile has no keyslot for key share files".into(),
+        });
+    }
+    Ok(Some(&header.file_id))
+}
+
+/// Reconstruct a slot key from the embedded shares sealed to `identities`.
+///
+/// Every embedded keyslot is tried in turn. Returns the key and the id of
+/// the first keyslot enough of the shares unlock.
+fn reconstruct_embedded(
+    safe_path: &Path,
+    header: &SafeHeader,
+    identities: &[Identity],
+) -> Result<(Vec<u8>, [u8; 8]), Error> {
+    let mut err = None;
+    for slot in &header.keyslots {
+        let KeyslotKind::Embedded { shares, .. } = &slot.kind else {
+            continue;
+        };
 
+        let mut opened = Vec::new();
+        for (i, sealed) in shares.iter().enumerate() {
+            if !identities.iter().any(|id| id.can_open(sealed)) {
+                continue;
+            }
+            // Embedded shares are reported as `<safefile>#share_001`, ...
+            let path = PathBuf::from(format!("{}#share_{:03}", safe_path.display(), i + 1));
+            match shamir::open_sealed(sealed, identities, &path) {
+                Ok(sfile) => opened.push((path, sfile)),
+                Err(e) => {
+                    for (_, s) in opened.iter_mut() {
+                        s.share.zeroize();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        if opened.is_empty() {
+            continue;
+        }
+
+        match shamir::combine_shares(opened, Some(&header.file_id)) {
+            Err(e @ Error::NotEnoughShares { .. }) => err = Some(e),
+            res => return res,
+        }
+    }
+
+    Err(err.unwrap_or_else(|| {
+        let has_embedded = header
+            .keyslots
+            .iter()
+            .any(|s| matches!(s.kind, KeyslotKind::Embedded { .. }));
+        Error::InvalidArgument {
+            details: if has_embedded {
+                "no embedded key share is sealed to the given identities".into()
+            } else {
+                "safefile has no embedded key shares, pass share files or a password".into()
+            },
+        }
+    }))
+}
+
+/// Seal the shares of a new Shamir keyslot into the slot itself if `split`
+/// has no output directory.
+fn embed_shares(
+    slot: &mut Keyslot,
+    slot_key: &[u8; 32],
+    split: &ShareOptions,
+    meta: &ShareMeta,
+) -> Result<(), Error> {
+    if split.outdir.is_some() {
+        return Ok(());
+    }
+
+    let shares = shamir::seal_shares(slot_key, split.threshold, meta, split.recipients)?;
+    let kind = KeyslotKind::Embedded {
+        threshold: split.threshold,
+        shares,
+    };
+    keyslot::set_kind(slot, slot_key, &meta.file_id, kind)
+}
+
+/// Stage a copy of `safe_path` with its header replaced by `header`.
+///
+/// The payload starting at `data_start` is copied unchanged, which is valid
+/// as long as only the keyslots differ.
+fn stage_with_header(
+    safe_path: &Path,
+    header: &SafeHeader,
+    data_start: u64,
+) -> Result<AtomicFile, Error> {
+    let mut src = File::open(safe_path).map_err(|e| Error::Io {
+        path: Some(safe_path.to_path_buf()),
+        source: e,
+    })?;
+    if format::is_armored_file(&mut src)? {
+        return Err(Error::InvalidArgument {
+            details: "armored safefiles cannot be modified, dearmor it first".into(),
+        });
+    }
+    src.seek(SeekFrom::Start(data_start))?;
+
+    let mut staged = AtomicFile::create(safe_path)?;
+    let mut writer = BufWriter::new(&mut staged);
+    format::write_header(&mut writer, header)?;
+    std::io::copy(&mut src, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    Ok(staged)
+}
+
+/// Decrypt `safe_path`, whose header is `header`, into `output` with an
+/// unlocked and verified `key`.
+fn decrypt_with_key<F>(
+    safe_path: &Path,
+    header: &SafeHeader,
+    output: Output,
+    mut key: [u8; 32],
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<DecryptResult, Error>
+where
+    F: FnMut(u64, u64),
+{
+    let output_file = match output {
+        Output::File(path) if header.content == Content::Archive => {
+            // The tree is unpacked into a staged directory, moved into place
+            // once the whole archive has authenticated.
+            let staged = AtomicDir::create(path)?;
+            let res = crypto::decrypt_tree(safe_path, staged.path(), key, options, progress_callback);
+            key.zeroize();
+
+            res?;
+            staged.commit()?;
+            path.to_string_lossy().into_owned()
+        }
+        Output::File(path) => {
+            // Plaintext is staged next to `output` and only moved into place
+            // once the whole file has authenticated; dropping `staged` on
+            // error removes it.
+            let mut staged = AtomicFile::create(path)?;
+            let res = crypto::decrypt_stream(safe_path, &mut staged, key, options, progress_callback);
+            key.zeroize();
+
+            res?;
+            staged.commit()?;
+            path.to_string_lossy().into_owned()
+        }
+        Output::Stream(writer) => {
+            let res = crypto::decrypt_stream(safe_path, writer, key, options, progress_callback);
+            key.zeroize();
+
+            res?;
+            "-".to_string()
+        }
+    };
+
+    // Read back for the length, which is kept in the trailer.
     let info = format::inspect_safe_from_path(safe_path)?;
 
-    Ok(DecryptResult {
-        output_file: output.to_string_lossy().into_owned(),
-        info: format::SafeInfo {
-            version: info.version,
-            timestamp: info.timestamp,
-            label: info.label,
-            ciphertext_len: info.ciphertext_len,
+    Ok(DecryptResult { output_file, info })
+}
+
+/// Encrypt `input` into the staged `output` under `key`.
+fn encrypt_input<F>(
+    input: Input,
+    output: &mut AtomicFile,
+    key: [u8; 32],
+    header: SafeHeader,
+    options: &CryptoOptions,
+    progress_callback: F,
+) -> Result<(), Error>
+where
+    F: FnMut(u64, u64),
+{
+    match input {
+        Input::File(path) => crypto::encrypt_stream(path, output, key, header, options, progress_callback),
+        Input::Stream(reader) => {
+            crypto::encrypt(reader, BufWriter::new(output), key, &header, options, progress_callback)?;
+            Ok(())
+        }
+        Input::Directory(dir) => {
+            // The staged safefile must not end up archiving itself.
+            let inside = output
+                .path()
+                .canonicalize()
+                .and_then(|out| Ok(out.starts_with(dir.canonicalize()?)))?;
+            if inside {
+                return Err(Error::InvalidArgument {
+                    details: format!("the output cannot be inside '{}'", dir.display()),
+                });
+            }
+
+            let header = SafeHeader {
+                content: Content::Archive,
+                ..header
+            };
+            crypto::encrypt_tree(dir, output, key, header, options, progress_callback)
+        }
+    }
+}
+
+/// Random values generated for every new safefile.
+struct FileSecrets {
+    key: [u8; 32],
+    nonce: [u8; 12],
+    file_id: [u8; 16],
+}
+
+impl FileSecrets {
+    fn generate() -> Self {
+        let mut secrets = FileSecrets {
+            key: [0u8; 32],
+            nonce: [0u8; 12],
+            file_id: [0u8; 16],
+        };
+        OsRng.fill_bytes(&mut secrets.key);
+        OsRng.fill_bytes(&mut secrets.nonce);
+        OsRng.fill_bytes(&mut secrets.file_id);
+        secrets
+    }
+
+    fn zeroize(&mut self) {
+        self.key.zeroize();
+        self.nonce.zeroize();
+    }
+}
+
+/// Build the header of a new safefile.
+///
+/// The length is left out of the header and recorded in the trailer once
+/// the input has been read.
+fn new_header(
+    secrets: &FileSecrets,
+    label: Option<&str>,
+    options: &CryptoOptions,
+    keyslots: Vec<Keyslot>,
+) -> Result<SafeHeader, Error> {
+    let ts = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|e| Error::InternalError {
+            details: format!("time error: {}", e),
+        })?
+        .as_secs();
+
+    Ok(SafeHeader {
+        version: consts::VERSION,
+        timestamp: ts,
+        label: label.unwrap_or("").to_string(),
+        file_id: secrets.file_id,
+        nonce: secrets.nonce,
+        cipher: options.cipher,
+        compression: match options.compression_level {
+            Some(_) => Compression::Zstd,
+            None => Compression::None,
         },
+        content: Content::File,
+        chunk_size: consts::CHUNK_SIZE,
+        ciphertext_len: None,
+        key_check: crypto::key_check(&secrets.key, &secrets.file_id),
+        keyslots,
     })
 }
@@ -0,0 +1,237 @@
+//! QR code rendering of share files for paper backups.
+//!
+//! The QR payload is the share armored without headers, so the text a
+//! scanner app produces can be saved to a file and used with decrypt as is;
+//! a scan or photo of the code can also be used directly, see
+//! `decode_image`. The headers and a fingerprint of the share are printed
+//! as a caption.
+
+use crate::armor::{self, ArmorKind};
+use crate::error::Error;
+use crate::shamir::ShareExport;
+use crate::utils::xml_escape;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::mono_font::ascii::FONT_10X20;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Baseline, Text};
+use image::{GrayImage, ImageFormat, Luma};
+use qrcode::{Color, EcLevel, QrCode};
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Pixels per QR module in images.
+const MODULE_PX: u32 = 8;
+/// Quiet zone around the code, in modules.
+const QUIET_ZONE: u32 = 4;
+/// Height of a caption line in PNG images.
+const LINE_PX: u32 = 24;
+
+/// Read the payload of the single QR code in the image `raw`, read from
+/// `path`.
+pub fn decode_image(raw: &[u8], path: &Path) -> Result<Vec<u8>, Error> {
+    let invalid = |details: String| Error::InvalidFormat {
+        path: path.to_path_buf(),
+        details,
+    };
+    let img = image::load_from_memory(raw)
+        .map_err(|e| invalid(format!("cannot read image: {}", e)))?
+        .to_luma8();
+
+    let mut prepared = rqrr::PreparedImage::prepare(img);
+    let grids = prepared.detect_grids();
+    let [grid] = grids.as_slice() else {
+        return Err(invalid(format!(
+            "expected one QR code in the image, found {}",
+            grids.len()
+        )));
+    };
+
+    let mut payload = Vec::new();
+    grid.decode_to(&mut payload)
+        .map_err(|e| invalid(format!("cannot decode QR code: {}", e)))?;
+    Ok(payload)
+}
+
+/// Whether `raw` looks like an SVG document, which cannot be scanned.
+pub fn is_svg(raw: &[u8]) -> bool {
+    let text = raw.trim_ascii_start();
+    text.starts_with(b"<?xml") || text.starts_with(b"<svg")
+}
+
+/// A share prepared for rendering as a QR code.
+pub struct ShareQr {
+    width: u32,
+    modules: Vec<bool>,
+    caption: Vec<String>,
+}
+
+impl ShareQr {
+    /// Build the QR code for an exported share read from `path`.
+    pub fn new(export: &ShareExport, path: &Path) -> Result<Self, Error> {
+        let text = armor::encode(ArmorKind::Share, &[], &export.raw);
+        let code =
+            QrCode::with_error_correction_level(text.as_bytes(), EcLevel::M).map_err(|e| {
+                Error::InvalidArgument {
+                    details: format!("'{}' does not fit in a QR code: {}", path.display(), e),
+                }
+            })?;
+
+        let mut caption: Vec<String> = export
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect();
+        caption.push(format!("Fingerprint: {}", export.fingerprint()));
+
+        Ok(Self {
+            width: code.width() as u32,
+            modules: code
+                .to_colors()
+                .into_iter()
+                .map(|c| c == Color::Dark)
+                .collect(),
+            caption,
+        })
+    }
+
+    /// Whether the module at (`x`, `y`) is dark; the quiet zone is light.
+    fn dark(&self, x: u32, y: u32) -> bool {
+        let (x, y) = (x.wrapping_sub(QUIET_ZONE), y.wrapping_sub(QUIET_ZONE));
+        x < self.width && y < self.width && self.modules[(y * self.width + x) as usize]
+    }
+
+    /// Side of the code including the quiet zone, in modules.
+    fn side(&self) -> u32 {
+        self.width + 2 * QUIET_ZONE
+    }
+
+    /// Render as a PNG image with the caption below the code.
+    pub fn to_png(&self) -> Result<Vec<u8>, Error> {
+        let side = self.side() * MODULE_PX;
+        let text_px = self.caption.len() as u32 * LINE_PX + MODULE_PX * QUIET_ZONE;
+        let mut img = Canvas(GrayImage::from_pixel(side, side + text_px, Luma([255])));
+
+        for y in 0..self.side() {
+            for x in 0..self.side() {
+                if !self.dark(x, y) {
+                    continue;
+                }
+                for dy in 0..MODULE_PX {
+                    for dx in 0..MODULE_PX {
+                        img.0
+                            .put_pixel(x * MODULE_PX + dx, y * MODULE_PX + dy, Luma([0]));
+                    }
+                }
+            }
+        }
+
+        let style = MonoTextStyle::new(&FONT_10X20, BinaryColor::On);
+        for (i, line) in self.caption.iter().enumerate() {
+            let origin = Point::new(
+                (QUIET_ZONE * MODULE_PX) as i32,
+                (side + i as u32 * LINE_PX) as i32,
+            );
+            let _ = Text::with_baseline(line, origin, style, Baseline::Top).draw(&mut img);
+        }
+
+        let mut png = Vec::new();
+        img.0
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .map_err(|e| Error::InvalidArgument {
+                details: format!("failed to encode PNG: {}", e),
+            })?;
+        Ok(png)
+    }
+
+    /// Render as an SVG document with the caption below the code.
+    pub fn to_svg(&self) -> String {
+        let side = self.side();
+        // Caption lines are 2 modules apart, with the font sized to match.
+        let height = side + self.caption.len() as u32 * 2 + QUIET_ZONE;
+        let mut out = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {side} {height}\" \
+             width=\"{}\" height=\"{}\" shape-rendering=\"crispEdges\">\n\
+             <rect width=\"100%\" height=\"100%\" fill=\"#fff\"/>\n<path fill=\"#000\" d=\"",
+            side * MODULE_PX,
+            height * MODULE_PX,
+        );
+        for y in 0..side {
+            for x in 0..side {
+                if self.dark(x, y) {
+                    out += &format!("M{x} {y}h1v1h-1z");
+                }
+            }
+        }
+        out += "\"/>\n";
+
+        for (i, line) in self.caption.iter().enumerate() {
+            out += &format!(
+                "<text x=\"{}\" y=\"{}\" font-family=\"monospace\" font-size=\"1.6\">{}</text>\n",
+                QUIET_ZONE,
+                side + 2 * i as u32 + 1,
+                xml_escape(line),
+            );
+        }
+        out += "</svg>\n";
+        out
+    }
+
+    /// Render with half block characters for a terminal, followed by the caption.
+    ///
+    /// Dark modules are drawn as spaces so the code scans on dark themes.
+    pub fn to_terminal(&self) -> String {
+        let side = self.side();
+        let mut out = String::new();
+        for y in (0..side).step_by(2) {
+            for x in 0..side {
+                out.push(
+                    match (self.dark(x, y), y + 1 < side && self.dark(x, y + 1)) {
+                        (false, false) => '█',
+                        (false, true) => '▀',
+                        (true, false) => '▄',
+                        (true, true) => ' ',
+                    },
+                );
+            }
+            out.push('\n');
+        }
+        for line in &self.caption {
+            out += line;
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Grayscale image as a drawing target for the caption font.
+struct Canvas(GrayImage);
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        Size::new(self.0.width(), self.0.height())
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            let (Ok(x), Ok(y)) = (u32::try_from(p.x), u32::try_from(p.y)) else {
+                continue;
+            };
+            if x < self.0.width() && y < self.0.height() {
+                let v = if color.is_on() { 0 } else { 255 };
+                self.0.put_pixel(x, y, Luma([v]));
+            }
+        }
+        Ok(())
+    }
+}
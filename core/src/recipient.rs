@@ -0,0 +1,392 @@
+//! Holder identities and sealing of key shares to their public keys.
+//!
+//! A holder key is either an X25519 key or a hybrid of X25519 and
+//! ML-KEM-768, which keeps shares sealed as long as either algorithm holds.
+
+use crate::consts;
+use crate::container::{RecipientKind, SealedShare};
+use crate::error::Error;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use ml_kem::ml_kem_768::{DecapsulationKey, EncapsulationKey};
+use ml_kem::{Decapsulate, Encapsulate, Key, KeyExport, Seed};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+/// Follows the kind name in the secret key line of an identity file.
+const SECRET_SUFFIX: &str = "-secret:";
+/// Size of an ML-KEM-768 ciphertext.
+const MLKEM_CIPHERTEXT_LEN: usize = 1088;
+
+/// Public key a share can be sealed to.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Recipient {
+    X25519([u8; 32]),
+    /// X25519 public key and ML-KEM-768 encapsulation key.
+    MlKem768X25519 { x25519: [u8; 32], mlkem: Vec<u8> },
+}
+
+impl Recipient {
+    pub fn kind(&self) -> RecipientKind {
+        match self {
+            Recipient::X25519(_) => RecipientKind::X25519,
+            Recipient::MlKem768X25519 { .. } => RecipientKind::MlKem768X25519,
+        }
+    }
+
+    /// Identifier recorded in the shares sealed to this key: the X25519 key
+    /// itself, or the SHA-256 of a hybrid key.
+    pub fn id(&self) -> [u8; 32] {
+        match self {
+            Recipient::X25519(key) => *key,
+            Recipient::MlKem768X25519 { .. } => Sha256::digest(self.to_bytes()).into(),
+        }
+    }
+
+    /// Short identifier of the public key, see `fingerprint`.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.kind(), &self.id())
+    }
+
+    /// Binary public key, the X25519 key first for hybrid keys.
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Recipient::X25519(key) => key.to_vec(),
+            Recipient::MlKem768X25519 { x25519, mlkem } => [x25519.as_slice(), mlkem].concat(),
+        }
+    }
+}
+
+/// Short identifier of the public key a share of `kind` with recipient
+/// `id` is sealed to: the first 8 bytes of the key's SHA-256 in hex.
+pub fn fingerprint(kind: RecipientKind, id: &[u8; 32]) -> String {
+    match kind {
+        RecipientKind::X25519 => hex::encode(&Sha256::digest(id)[..8]),
+        RecipientKind::MlKem768X25519 => hex::encode(&id[..8]),
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.kind().name(), hex::encode(self.to_bytes()))
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = Error;
+
+    /// Parse a public key of the form `<kind>:<hex key>`, such as
+    /// `x25519:<64 hex digits>`.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let s = s.trim();
+        let (kind, hex_key) = RecipientKind::ALL
+            .into_iter()
+            .find_map(|k| Some((k, s.strip_prefix(k.name())?.strip_prefix(':')?)))
+            .ok_or_else(|| Error::InvalidRecipient {
+                details: format!(
+                    "expected a prefix of {}",
+                    RecipientKind::ALL.map(|k| format!("'{}:'", k.name())).join(" or ")
+                ),
+            })?;
+
+        let raw = hex::decode(hex_key).map_err(|e| Error::InvalidRecipient {
+            details: e.to_string(),
+        })?;
+        let invalid_len = || Error::InvalidRecipient {
+            details: format!("invalid {} key length", kind),
+        };
+        match kind {
+            RecipientKind::X25519 => Ok(Recipient::X25519(
+                raw.try_into().map_err(|_| invalid_len())?,
+            )),
+            RecipientKind::MlKem768X25519 => {
+                if raw.len() <= 32 {
+                    return Err(invalid_len());
+                }
+                let (x25519, mlkem) = raw.split_at(32);
+                encapsulation_key(mlkem)?;
+                Ok(Recipient::MlKem768X25519 {
+                    x25519: x25519.try_into().expect("checked length"),
+                    mlkem: mlkem.to_vec(),
+                })
+            }
+        }
+    }
+}
+
+/// Decode and check an ML-KEM-768 encapsulation key.
+fn encapsulation_key(raw: &[u8]) -> Result<EncapsulationKey, Error> {
+    Key::<EncapsulationKey>::try_from(raw)
+        .ok()
+        .and_then(|key| EncapsulationKey::new(&key).ok())
+        .ok_or_else(|| Error::InvalidRecipient {
+            details: "invalid ML-KEM-768 key".into(),
+        })
+}
+
+/// Secret key of a share holder.
+///
+/// `mlkem` holds the ML-KEM-768 key of a hybrid identity.
+pub struct Identity {
+    secret: StaticSecret,
+    mlkem: Option<DecapsulationKey>,
+}
+
+impl Identity {
+    /// Generate a new random identity of `kind`.
+    pub fn generate(kind: RecipientKind) -> Self {
+        let mlkem = match kind {
+            RecipientKind::X25519 => None,
+            RecipientKind::MlKem768X25519 => {
+                let mut seed = [0u8; 64];
+                OsRng.fill_bytes(&mut seed);
+                let key = DecapsulationKey::from_seed(Seed::from(seed));
+                seed.zeroize();
+                Some(key)
+            }
+        };
+
+        Identity {
+            secret: StaticSecret::random_from_rng(OsRng),
+            mlkem,
+        }
+    }
+
+    pub fn kind(&self) -> RecipientKind {
+        match self.mlkem {
+            None => RecipientKind::X25519,
+            Some(_) => RecipientKind::MlKem768X25519,
+        }
+    }
+
+    /// Public key matching this identity.
+    pub fn recipient(&self) -> Recipient {
+        let x25519 = PublicKey::from(&self.secret).to_bytes();
+        match &self.mlkem {
+            None => Recipient::X25519(x25519),
+            Some(key) => Recipient::MlKem768X25519 {
+                x25519,
+                mlkem: key.encapsulation_key().to_bytes().to_vec(),
+            },
+        }
+    }
+
+    /// Whether `sealed` is sealed to this identity.
+    pub fn can_open(&self, sealed: &SealedShare) -> bool {
+        let recipient = self.recipient();
+        recipient.kind() == sealed.kind && recipient.id() == sealed.recipient
+    }
+
+    /// Read an identity file written by `write_to_path`.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn read_from_path(path: &Path) -> Result<Self, Error> {
+        let mut text = fs::read_to_string(path).map_err(|e| Error::Io {
+            path: Some(path.to_path_buf()),
+            source: e,
+        })?;
+
+        // The X25519 secret, then the ML-KEM seed of hybrid identities.
+        let mut secret = [0u8; 96];
+        let kind = text
+            .lines()
+            .map(str::trim)
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .and_then(|l| {
+                RecipientKind::ALL.into_iter().find_map(|kind| {
+                    let h = l.strip_prefix(kind.name())?.strip_prefix(SECRET_SUFFIX)?;
+                    let len = match kind {
+                        RecipientKind::X25519 => 32,
+                        RecipientKind::MlKem768X25519 => 96,
+                    };
+                    hex::decode_to_slice(h, &mut secret[..len]).ok().map(|_| kind)
+                })
+            });
+        text.zeroize();
+
+        let Some(kind) = kind else {
+            secret.zeroize();
+            return Err(Error::InvalidIdentity {
+                path: path.to_path_buf(),
+            });
+        };
+
+        let mut x25519 = [0u8; 32];
+        x25519.copy_from_slice(&secret[..32]);
+        let mut seed = [0u8; 64];
+        seed.copy_from_slice(&secret[32..]);
+        let identity = Identity {
+            secret: StaticSecret::from(x25519),
+            mlkem: (kind == RecipientKind::MlKem768X25519)
+                .then(|| DecapsulationKey::from_seed(Seed::from(seed))),
+        };
+        secret.zeroize();
+        x25519.zeroize();
+        seed.zeroize();
+        Ok(identity)
+    }
+
+    /// Write the identity to a new file readable only by its owner.
+    ///
+    /// Fails if `path` already exists.
+    pub fn write_to_path(&self, path: &Path) -> Result<(), Error> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(path).map_err(|e| Error::Io {
+            path: Some(path.to_path_buf()),
+            source: e,
+        })?;
+
+        let mut secret = hex::encode(self.secret.to_bytes());
+        if let Some(key) = &self.mlkem {
+            let mut seed = key.to_bytes();
+            secret.push_str(&hex::encode(seed));
+            seed.zeroize();
+        }
+        let res = write!(
+            file,
+            "# safefile identity, keep this file secret\n# public key: {}\n{}{}{}\n",
+            self.recipient(),
+            self.kind().name(),
+            SECRET_SUFFIX,
+            secret
+        )
+        .and_then(|_| file.sync_all());
+        secret.zeroize();
+
+        res.map_err(|e| Error::Io {
+            path: Some(path.to_path_buf()),
+            source: e,
+        })
+    }
+}
+
+/// Derive the AES key sealing a share from the shared secret `ikm`, bound
+/// to the `ephemeral` key and the recipient `id` under `context`.
+fn seal_key(ikm: &[u8], ephemeral: &[u8; 32], id: &[u8; 32], context: &[u8]) -> [u8; 32] {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral);
+    salt[32..].copy_from_slice(id);
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), ikm)
+        .expand(context, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Derive the AES key sealing a share to a hybrid recipient from both
+/// shared secrets, so it stays secret as long as either one does.
+fn hybrid_seal_key(
+    mlkem_shared: &[u8],
+    x25519_shared: &[u8; 32],
+    ephemeral: &[u8; 32],
+    id: &[u8; 32],
+) -> [u8; 32] {
+    let mut ikm = [mlkem_shared, x25519_shared.as_slice()].concat();
+    let key = seal_key(&ikm, ephemeral, id, consts::HYBRID_SEAL_CONTEXT);
+    ikm.zeroize();
+    key
+}
+
+/// Encrypt an encoded share file to `recipient`.
+///
+/// A fresh ephemeral key, and for hybrid recipients a fresh ML-KEM
+/// encapsulation, is used for every share, so the sealing key is never
+/// reused and a fixed nonce is safe.
+pub fn seal(recipient: &Recipient, plaintext: &[u8]) -> Result<SealedShare, Error> {
+    let x25519 = match recipient {
+        Recipient::X25519(key) => key,
+        Recipient::MlKem768X25519 { x25519, .. } => x25519,
+    };
+    let eph_secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral = PublicKey::from(&eph_secret).to_bytes();
+    let shared = eph_secret.diffie_hellman(&PublicKey::from(*x25519));
+    if !shared.was_contributory() {
+        return Err(Error::InvalidRecipient {
+            details: "low-order public key".into(),
+        });
+    }
+
+    let id = recipient.id();
+    // The ML-KEM ciphertext, if any, precedes the sealed share.
+    let mut sealed = Vec::new();
+    let mut key = match recipient {
+        Recipient::X25519(_) => seal_key(shared.as_bytes(), &ephemeral, &id, consts::SEAL_CONTEXT),
+        Recipient::MlKem768X25519 { mlkem, .. } => {
+            let (ct, mut mlkem_shared) = encapsulation_key(mlkem)?.encapsulate();
+            sealed.extend_from_slice(&ct);
+            let key = hybrid_seal_key(&mlkem_shared, shared.as_bytes(), &ephemeral, &id);
+            mlkem_shared.zeroize();
+            key
+        }
+    };
+    let ciphertext = Aes256Gcm::new((&key).into())
+        .encrypt(Nonce::from_slice(&[0u8; 12]), plaintext)
+        .map_err(|_e| Error::InternalError {
+            details: "share sealing failed".into(),
+        });
+    key.zeroize();
+    sealed.extend_from_slice(&ciphertext?);
+
+    Ok(SealedShare {
+        version: consts::SEALED_SHARE_VERSION,
+        kind: recipient.kind(),
+        recipient: id,
+        ephemeral,
+        ciphertext: sealed,
+    })
+}
+
+/// Decrypt a sealed share read from `path` with whichever of `identities`
+/// it is sealed to.
+pub fn open(sealed: &SealedShare, identities: &[Identity], path: &Path) -> Result<Vec<u8>, Error> {
+    let identity = identities
+        .iter()
+        .find(|id| id.can_open(sealed))
+        .ok_or_else(|| Error::NoMatchingIdentity {
+            path: path.to_path_buf(),
+        })?;
+    let corrupted = || Error::ShareCorrupted {
+        path: path.to_path_buf(),
+    };
+
+    let shared = identity
+        .secret
+        .diffie_hellman(&PublicKey::from(sealed.ephemeral));
+    let (mut key, ciphertext) = match &identity.mlkem {
+        None => (
+            seal_key(shared.as_bytes(), &sealed.ephemeral, &sealed.recipient, consts::SEAL_CONTEXT),
+            sealed.ciphertext.as_slice(),
+        ),
+        Some(mlkem) => {
+            if sealed.ciphertext.len() < MLKEM_CIPHERTEXT_LEN {
+                return Err(corrupted());
+            }
+            let (ct, ciphertext) = sealed.ciphertext.split_at(MLKEM_CIPHERTEXT_LEN);
+            let mut mlkem_shared = mlkem.decapsulate_slice(ct).map_err(|_e| corrupted())?;
+            let key = hybrid_seal_key(&mlkem_shared, shared.as_bytes(), &sealed.ephemeral, &sealed.recipient);
+            mlkem_shared.zeroize();
+            (key, ciphertext)
+        }
+    };
+    let plaintext = Aes256Gcm::new((&key).into())
+        .decrypt(Nonce::from_slice(&[0u8; 12]), ciphertext);
+    key.zeroize();
+
+    plaintext.map_err(|_e| corrupted())
+}
@@ -1,24 +1,37 @@
 //! Shamir Secret Sharing helpers and share file handling.
 
-use crate::container::ShareFile;
+use crate::armor::{self, ArmorKind};
+use crate::atomic::AtomicFile;
+use crate::consts;
+use crate::container::{LegacySealedShare, LegacyShareFile, SealedShare, ShareFile};
 use crate::error::Error;
+use crate::mnemonic;
+use crate::qr;
+use crate::recipient::{self, Identity, Recipient};
 use sha2::{Digest, Sha256};
 use sss_rs::prelude::share;
 use std::fs;
-use std::path::{Path};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use zeroize::Zeroize;
 
-/// Create SSS shares for `key` and save them to `outdir`.
-///
-/// The files are named `share_001.bin`, `share_002.bin`, ...
-pub fn create_shares(
+/// Metadata copied into every share of a split.
+pub struct ShareMeta<'a> {
+    pub label: &'a str,
+    pub timestamp: u64,
+    pub file_id: [u8; 16],
+    pub slot_id: [u8; 8],
+}
+
+/// Split `key` into `shares` SSS shares, `threshold` of which are needed to
+/// reconstruct it, carrying the `file_id` of the safefile and the `slot_id`
+/// of the keyslot they unlock.
+pub fn split_key(
     key: &[u8],
     threshold: u8,
     shares: u8,
-    outdir: &Path,
-    label: &str,
-    timestamp: u64,
-) -> Result<Vec<String>, Error> {
+    meta: &ShareMeta,
+) -> Result<Vec<ShareFile>, Error> {
     let mut shares_vec = share(key, threshold, shares, true).map_err(|e| match e {
         sss_rs::wrapped_sharing::Error::IOError(io_err) => io_err.into(),
         sss_rs::wrapped_sharing::Error::OtherSharingError(basic) => match basic {
@@ -33,7 +46,7 @@ pub fn create_shares(
         _ => Error::SharingFailed { details: format!("sharing failed: {}", e) },
     })?;
 
-    let mut paths = Vec::new();
+    let mut files = Vec::with_capacity(shares_vec.len());
     for (i, s) in shares_vec.iter().enumerate() {
         let mut hasher = Sha256::new();
         hasher.update(s);
@@ -41,52 +54,506 @@ pub fn create_shares(
         let mut checksum = [0u8; 32];
         checksum.copy_from_slice(&digest);
 
-        let sf = ShareFile {
-            version: 1,
-            timestamp,
-            label: label.to_string(),
+        files.push(ShareFile {
+            version: consts::SHARE_VERSION,
+            timestamp: meta.timestamp,
+            label: meta.label.to_string(),
+            file_id: meta.file_id,
+            slot_id: meta.slot_id,
+            index: (i + 1) as u8,
+            threshold,
+            total: shares,
             share: s.clone(),
             checksum,
-        };
-
-        let data = bincode::serde::encode_to_vec(&sf, bincode::config::standard())?;
-        let filename = outdir.join(format!("share_{:03}.bin", i + 1));
-        fs::write(&filename, data)?;
-        paths.push(filename.to_string_lossy().into_owned());
+        });
     }
 
-    // Zero shares in memory after writing to disk
     for s in shares_vec.iter_mut() {
         s.zeroize();
     }
 
-    Ok(paths)
+    Ok(files)
+}
+
+/// Create SSS shares for `key` and stage them in `outdir`.
+///
+/// The files are named after the keyslot, `share_<slot>_001.bin`,
+/// `share_<slot>_002.bin`, ... When `recipients` are given, there must be
+/// one per share and share `i` is sealed to recipient `i` and named
+/// `share_<slot>_00i.sealed` instead. Existing files are never replaced.
+/// Nothing appears under the final names until `StagedShares::commit`.
+pub fn create_shares(
+    key: &[u8],
+    threshold: u8,
+    shares: u8,
+    outdir: &Path,
+    meta: &ShareMeta,
+    recipients: &[Recipient],
+) -> Result<StagedShares, Error> {
+    if !recipients.is_empty() {
+        check_recipients(recipients, shares)?;
+    }
+
+    let mut files = split_key(key, threshold, shares, meta)?;
+
+    let staged = write_shares(&files, outdir, &hex::encode(meta.slot_id), recipients);
+
+    // Zero shares in memory after writing to disk
+    for sf in files.iter_mut() {
+        sf.share.zeroize();
+    }
+
+    staged
+}
+
+/// Share files written to temporary files by `create_shares`.
+///
+/// Dropping them uncommitted removes every one of them.
+pub struct StagedShares {
+    files: Vec<(AtomicFile, String)>,
+}
+
+impl StagedShares {
+    /// Move every share file to its final path and return the paths.
+    pub fn commit(self) -> Result<Vec<String>, Error> {
+        let mut paths = Vec::with_capacity(self.files.len());
+        for (staged, path) in self.files {
+            staged.commit()?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+/// Stage `files` in `outdir`, sealing file `i` to recipient `i` if given.
+fn write_shares(
+    files: &[ShareFile],
+    outdir: &Path,
+    slot: &str,
+    recipients: &[Recipient],
+) -> Result<StagedShares, Error> {
+    let mut staged = StagedShares { files: Vec::new() };
+    for (i, sf) in files.iter().enumerate() {
+        let mut data = encode_share(sf)?;
+        let filename = match recipients.get(i) {
+            Some(r) => {
+                let sealed = recipient::seal(r, &data);
+                data.zeroize();
+                data = encode_sealed_share(&sealed?)?;
+                outdir.join(format!("share_{}_{:03}.sealed", slot, i + 1))
+            }
+            None => outdir.join(format!("share_{}_{:03}.bin", slot, i + 1)),
+        };
+        let res = AtomicFile::create_new(&filename).and_then(|mut file| {
+            file.write_all(&data)?;
+            Ok(file)
+        });
+        data.zeroize();
+        staged.files.push((res?, filename.to_string_lossy().into_owned()));
+    }
+    Ok(staged)
+}
+
+/// Create SSS shares for `key` and seal share `i` to recipient `i`.
+///
+/// The sealed shares are returned for embedding in the safefile header.
+pub fn seal_shares(
+    key: &[u8],
+    threshold: u8,
+    meta: &ShareMeta,
+    recipients: &[Recipient],
+) -> Result<Vec<SealedShare>, Error> {
+    let shares = check_recipients(recipients, recipients.len() as u8)?;
+    let mut files = split_key(key, threshold, shares, meta)?;
+
+    let sealed = files
+        .iter()
+        .zip(recipients)
+        .map(|(sf, r)| {
+            let mut data = encode_share(sf)?;
+            let sealed = recipient::seal(r, &data);
+            data.zeroize();
+            sealed
+        })
+        .collect();
+
+    for sf in files.iter_mut() {
+        sf.share.zeroize();
+    }
+
+    sealed
+}
+
+/// Check that there is exactly one recipient per share.
+fn check_recipients(recipients: &[Recipient], shares: u8) -> Result<u8, Error> {
+    if recipients.is_empty() || recipients.len() > 255 || recipients.len() != shares as usize {
+        return Err(Error::InvalidArgument {
+            details: format!(
+                "{} recipients given for {} shares",
+                recipients.len(),
+                shares
+            ),
+        });
+    }
+    Ok(shares)
+}
+
+/// Encode a share file into its on-disk form: magic followed by the
+/// encoded `ShareFile`.
+pub fn encode_share(sfile: &ShareFile) -> Result<Vec<u8>, Error> {
+    let encoded = bincode::serde::encode_to_vec(sfile, bincode::config::standard())?;
+
+    let mut out = Vec::with_capacity(consts::SHARE_MAGIC.len() + encoded.len());
+    out.extend_from_slice(consts::SHARE_MAGIC);
+    out.extend_from_slice(&encoded);
+
+    Ok(out)
+}
+
+/// Encode a sealed share into its on-disk form: magic followed by the
+/// encoded `SealedShare`.
+pub fn encode_sealed_share(sealed: &SealedShare) -> Result<Vec<u8>, Error> {
+    let encoded = bincode::serde::encode_to_vec(sealed, bincode::config::standard())?;
+
+    let mut out = Vec::with_capacity(consts::SEALED_SHARE_MAGIC.len() + encoded.len());
+    out.extend_from_slice(consts::SEALED_SHARE_MAGIC);
+    out.extend_from_slice(&encoded);
+
+    Ok(out)
+}
+
+/// Decode a share file read from `path`, upgrading legacy shares to the
+/// current layout.
+///
+/// Sealed shares are opened with whichever of `identities` they are sealed
+/// to, and armored and mnemonic text files and QR code images are decoded
+/// first.
+pub fn decode_share(raw: &[u8], path: &Path, identities: &[Identity]) -> Result<ShareFile, Error> {
+    if let Some(encoded) = raw.strip_prefix(consts::SEALED_SHARE_MAGIC.as_slice()) {
+        let sealed = decode_sealed_share(encoded, path)?;
+        return open_sealed(&sealed, identities, path);
+    }
+
+    let Some(encoded) = raw.strip_prefix(consts::SHARE_MAGIC.as_slice()) else {
+        // Version 1 shares predate the magic and start with their version.
+        if raw.first() == Some(&consts::LEGACY_SHARE_VERSION)
+            && let Ok((sfile, _)) = bincode::serde::decode_from_slice::<LegacyShareFile, _>(
+                raw,
+                bincode::config::standard(),
+            )
+        {
+            return Ok(sfile.into());
+        }
+        if armor::is_armored(raw) {
+            let data = armor::decode(&String::from_utf8_lossy(raw), ArmorKind::Share, path)?;
+            return decode_share(&data, path, identities);
+        }
+        if qr::is_svg(raw) {
+            return Err(Error::InvalidFormat {
+                path: path.to_path_buf(),
+                details: "QR codes can only be read from PNG or JPEG images, not SVG".into(),
+            });
+        }
+        if mnemonic::is_mnemonic(raw) {
+            let text = String::from_utf8_lossy(raw);
+            return mnemonic::decode(&text, &path.display().to_string());
+        }
+        if image::guess_format(raw).is_ok() {
+            let payload = qr::decode_image(raw, path)?;
+            return decode_share(&payload, path, identities);
+        }
+        return Err(Error::InvalidShareMagic { path: path.to_path_buf() });
+    };
+
+    // The version is the first encoded field and always a single byte.
+    let version = encoded.first().copied().unwrap_or(0);
+    if version != consts::SHARE_VERSION {
+        return Err(Error::UnsupportedShareVersion {
+            path: path.to_path_buf(),
+            version,
+        });
+    }
+
+    match bincode::serde::decode_from_slice::<ShareFile, _>(encoded, bincode::config::standard()) {
+        Ok((sfile, used)) if used == encoded.len() => Ok(sfile),
+        _ => Err(Error::ShareCorrupted { path: path.to_path_buf() }),
+    }
+}
+
+/// Decode a sealed share read from `path`, without its magic, upgrading
+/// legacy sealed shares to the current layout.
+fn decode_sealed_share(encoded: &[u8], path: &Path) -> Result<SealedShare, Error> {
+    let version = encoded.first().copied().unwrap_or(0);
+    let decoded = match version {
+        consts::SEALED_SHARE_VERSION => {
+            bincode::serde::decode_from_slice::<SealedShare, _>(encoded, bincode::config::standard())
+        }
+        consts::LEGACY_SEALED_SHARE_VERSION => bincode::serde::decode_from_slice::<LegacySealedShare, _>(
+            encoded,
+            bincode::config::standard(),
+        )
+        .map(|(sealed, used)| (sealed.into(), used)),
+        _ => {
+            return Err(Error::UnsupportedShareVersion {
+                path: path.to_path_buf(),
+                version,
+            });
+        }
+    };
+    match decoded {
+        Ok((sealed, used)) if used == encoded.len() => Ok(sealed),
+        _ => Err(Error::ShareCorrupted { path: path.to_path_buf() }),
+    }
+}
+
+/// A share in its binary on-disk form, with descriptive headers that can
+/// be shown without opening it. The bytes are zeroed on drop.
+pub struct ShareExport {
+    pub raw: Vec<u8>,
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl ShareExport {
+    /// Short fingerprint of the share bytes, to match copies of one share.
+    pub fn fingerprint(&self) -> String {
+        hex::encode(&Sha256::digest(&self.raw)[..8])
+    }
+}
+
+impl Drop for ShareExport {
+    fn drop(&mut self) {
+        self.raw.zeroize();
+    }
+}
+
+/// Read the share file at `path` for export to another encoding.
+///
+/// Sealed and legacy shares are exported as they are; mnemonic and armored
+/// input is converted back to the binary form first.
+pub fn export_share(path: &Path) -> Result<ShareExport, Error> {
+    let mut raw = fs::read(path).map_err(|e| Error::Io {
+        path: Some(path.to_path_buf()),
+        source: e,
+    })?;
+    if armor::is_armored(&raw) {
+        let data = armor::decode(&String::from_utf8_lossy(&raw), ArmorKind::Share, path);
+        raw.zeroize();
+        raw = data?;
+    }
+
+    if let Some(encoded) = raw.strip_prefix(consts::SEALED_SHARE_MAGIC.as_slice()) {
+        let sealed = decode_sealed_share(encoded, path);
+        let headers = match sealed {
+            Ok(sealed) => vec![(
+                "Recipient",
+                format!("{} {}", sealed.kind, recipient::fingerprint(sealed.kind, &sealed.recipient)),
+            )],
+            Err(e) => {
+                raw.zeroize();
+                return Err(e);
+            }
+        };
+        return Ok(ShareExport { raw, headers });
+    }
+
+    let mut export = ShareExport {
+        raw,
+        headers: Vec::new(),
+    };
+    let mut sfile = decode_share(&export.raw, path, &[])?;
+    if mnemonic::is_mnemonic(&export.raw) {
+        export.raw.zeroize();
+        export.raw = encode_share(&sfile)?;
+    }
+
+    export.headers = share_headers(&sfile);
+    sfile.share.zeroize();
+
+    Ok(export)
+}
+
+/// Export an opened share in its binary on-disk form.
+pub fn export_share_file(sfile: &ShareFile) -> Result<ShareExport, Error> {
+    Ok(ShareExport {
+        raw: encode_share(sfile)?,
+        headers: share_headers(sfile),
+    })
+}
+
+/// Descriptive headers of a plain share.
+fn share_headers(sfile: &ShareFile) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    if !sfile.label.is_empty() {
+        headers.push(("Label", sfile.label.clone()));
+    }
+    if sfile.version != consts::LEGACY_SHARE_VERSION {
+        headers.push(("Index", format!("{} of {}", sfile.index, sfile.total)));
+        headers.push(("Threshold", sfile.threshold.to_string()));
+    }
+    headers
+}
+
+/// Armor the share file at `path`, see `export_share`.
+pub fn armor_share(path: &Path) -> Result<String, Error> {
+    let export = export_share(path)?;
+    Ok(armor::encode(ArmorKind::Share, &export.headers, &export.raw))
+}
+
+/// Open a sealed share read from `path` with `identities` and decode it.
+pub fn open_sealed(
+    sealed: &SealedShare,
+    identities: &[Identity],
+    path: &Path,
+) -> Result<ShareFile, Error> {
+    let mut plain = recipient::open(sealed, identities, path)?;
+    // A sealed share always contains a plain one.
+    let sfile = if plain.starts_with(consts::SEALED_SHARE_MAGIC) {
+        Err(Error::ShareCorrupted { path: path.to_path_buf() })
+    } else {
+        decode_share(&plain, path, &[])
+    };
+    plain.zeroize();
+    sfile
+}
+
+/// Read and decode the share file at `path`, opening it with `identities`
+/// if it is sealed.
+pub fn read_share(path: &Path, identities: &[Identity]) -> Result<ShareFile, Error> {
+    let raw = fs::read(path).map_err(|e| Error::Io {
+        path: Some(path.to_path_buf()),
+        source: e,
+    })?;
+    decode_share(&raw, path, identities)
 }
 
 /// Reconstruct the original key from a set of share file paths.
 ///
-/// The function will validate each share's checksum.
-pub fn reconstruct_key(share_paths: &[&Path]) -> Result<Vec<u8>, Error> {
-    let mut shares_buf = Vec::new();
+/// Sealed shares are opened with `identities`. See `combine_shares` for the
+/// checks applied.
+///
+/// Returns the key and the `slot_id` of the keyslot it belongs to.
+pub fn reconstruct_key(
+    share_paths: &[&Path],
+    identities: &[Identity],
+    file_id: Option<&[u8; 16]>,
+) -> Result<(Vec<u8>, [u8; 8]), Error> {
+    let mut shares = Vec::with_capacity(share_paths.len());
     for p in share_paths {
-        let raw = fs::read(p)?;
-        let (sfile, _): (ShareFile, usize) = bincode::serde::decode_from_slice(&raw, bincode::config::standard())?;
+        match read_share(p, identities) {
+            Ok(sfile) => shares.push((p.to_path_buf(), sfile)),
+            Err(e) => {
+                zeroize_shares(&mut shares);
+                return Err(e);
+            }
+        }
+    }
+
+    combine_shares(shares, file_id)
+}
+
+/// Reconstruct the original key from typed share mnemonics.
+///
+/// Every mnemonic is decoded first, so mistyped words are reported before
+/// any reconstruction; see `combine_shares` for the remaining checks.
+pub fn reconstruct_from_mnemonics(
+    mnemonics: &[&str],
+    file_id: Option<&[u8; 16]>,
+) -> Result<(Vec<u8>, [u8; 8]), Error> {
+    let mut shares = Vec::with_capacity(mnemonics.len());
+    for (i, text) in mnemonics.iter().enumerate() {
+        let name = format!("mnemonic {}", i + 1);
+        match mnemonic::decode(text, &name) {
+            Ok(sfile) => shares.push((PathBuf::from(name), sfile)),
+            Err(e) => {
+                zeroize_shares(&mut shares);
+                return Err(e);
+            }
+        }
+    }
+
+    combine_shares(shares, file_id)
+}
+
+/// Reconstruct the original key from decoded shares, each paired with the
+/// path it was read from for error reporting.
+///
+/// The function will validate each share's checksum. When `file_id` is
+/// given, every share must belong to that safefile. Shares from different
+/// keyslots, duplicate shares and too few shares for the recorded threshold
+/// are also rejected; all of this is checked before any reconstruction is
+/// attempted.
+///
+/// Returns the key and the `slot_id` of the keyslot it belongs to.
+pub fn combine_shares(
+    mut shares: Vec<(PathBuf, ShareFile)>,
+    file_id: Option<&[u8; 16]>,
+) -> Result<(Vec<u8>, [u8; 8]), Error> {
+    let res = check_shares(&shares, file_id).and_then(|(required, slot_id)| {
+        if shares.len() < required as usize {
+            return Err(Error::NotEnoughShares {
+                provided: shares.len() as u8,
+                required,
+            });
+        }
+
+        let shares_buf: Vec<Vec<u8>> = shares.iter().map(|(_, s)| s.share.clone()).collect();
+        let key = sss_rs::prelude::reconstruct(&shares_buf, true);
+
+        // Zero share buffers
+        for mut s in shares_buf {
+            s.zeroize();
+        }
+
+        let key = key.map_err(|e| Error::InternalError { details: format!("reconstruct failed: {}", e) })?;
+        Ok((key, slot_id))
+    });
+
+    zeroize_shares(&mut shares);
+    res
+}
+
+/// Validate `shares` against each other and `file_id`.
+///
+/// Returns the recorded threshold and the common `slot_id`.
+fn check_shares(
+    shares: &[(PathBuf, ShareFile)],
+    file_id: Option<&[u8; 16]>,
+) -> Result<(u8, [u8; 8]), Error> {
+    let mut required: u8 = 0;
+    let mut slot_id: Option<[u8; 8]> = None;
+    for (i, (p, sfile)) in shares.iter().enumerate() {
+        if let Some(id) = file_id
+            && (sfile.version == consts::LEGACY_SHARE_VERSION || &sfile.file_id != id)
+        {
+            return Err(Error::ShareFileMismatch { path: p.clone() });
+        }
+        if slot_id.is_some_and(|id| id != sfile.slot_id) {
+            return Err(Error::ShareKeyslotMismatch { path: p.clone() });
+        }
+        slot_id = Some(sfile.slot_id);
 
         let mut hasher = Sha256::new();
         hasher.update(&sfile.share);
         let digest = hasher.finalize();
         if digest[..] != sfile.checksum[..] {
-            return Err(Error::ShareChecksumMismatch { path: p.to_path_buf() });
+            return Err(Error::ShareChecksumMismatch { path: p.clone() });
         }
-        shares_buf.push(sfile.share);
-    }
 
-    let key = sss_rs::prelude::reconstruct(&shares_buf, true).map_err(|e| Error::InternalError { details: format!("reconstruct failed: {}", e) })?;
+        // The first byte of an sss-rs share is its x-coordinate, which is
+        // unique within a split.
+        if shares[..i].iter().any(|(_, s)| s.share.first() == sfile.share.first()) {
+            return Err(Error::DuplicateShare { path: p.clone() });
+        }
 
-    // Zero share buffers
-    for s in shares_buf.iter_mut() {
-        s.zeroize();
+        // Legacy shares record no threshold (0).
+        required = required.max(sfile.threshold);
     }
 
-    Ok(key)
+    Ok((required, slot_id.unwrap_or_default()))
+}
+
+fn zeroize_shares(shares: &mut [(PathBuf, ShareFile)]) {
+    for (_, s) in shares.iter_mut() {
+        s.share.zeroize();
+    }
 }
@@ -0,0 +1,125 @@
+//! Printable share sheets for handing shares to their holders.
+//!
+//! A sheet is a self-contained HTML page holding one share as words, as
+//! armored text and as a QR code, with recovery instructions. Printing it
+//! from a browser produces the paper (or PDF) copy.
+
+use crate::armor::{self, ArmorKind};
+use crate::consts;
+use crate::container::ShareFile;
+use crate::error::Error;
+use crate::mnemonic;
+use crate::qr::ShareQr;
+use crate::shamir;
+use crate::utils::xml_escape;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::path::Path;
+
+const STYLE: &str = "\
+body { font-family: sans-serif; max-width: 46em; margin: 2em auto; color: #000; }
+h1 { font-size: 1.5em; border-bottom: 2px solid #000; padding-bottom: .3em; }
+h2 { font-size: 1.1em; margin-top: 1.5em; }
+table.facts td { padding: .15em 1em .15em 0; vertical-align: top; }
+table.facts td:first-child { font-weight: bold; }
+.blank { display: inline-block; min-width: 20em; border-bottom: 1px solid #000; }
+ol.words { columns: 4; font-family: monospace; font-size: 1.05em; padding-left: 3em; }
+ol.words li { padding: .1em 0; }
+pre { font-size: .8em; border: 1px solid #999; padding: .8em; white-space: pre-wrap; }
+.qr { text-align: center; break-inside: avoid; }
+.qr img { width: 22em; }
+.notice { border: 2px solid #000; padding: .5em 1em; }
+@media print { body { margin: 0; } h2 { break-after: avoid; } }
+";
+
+/// Render the share sheet of `sfile`, read from `path`, as an HTML page.
+///
+/// Without a `holder` the sheet has a blank line to write the name on.
+pub fn render(sfile: &ShareFile, holder: Option<&str>, path: &Path) -> Result<String, Error> {
+    if sfile.version == consts::LEGACY_SHARE_VERSION {
+        return Err(Error::InvalidArgument {
+            details: format!(
+                "'{}' is a version 1 share without a threshold; add a new share keyslot first",
+                path.display()
+            ),
+        });
+    }
+
+    let export = shamir::export_share_file(sfile)?;
+    let words = mnemonic::encode(sfile)?;
+    let armored = armor::encode(ArmorKind::Share, &export.headers, &export.raw);
+    let qr = STANDARD.encode(ShareQr::new(&export, path)?.to_svg());
+
+    let label = if sfile.label.is_empty() {
+        "(none)".to_string()
+    } else {
+        xml_escape(&sfile.label)
+    };
+    let file = if sfile.label.is_empty() {
+        "the encrypted file".to_string()
+    } else {
+        format!("the encrypted file &ldquo;{label}&rdquo;")
+    };
+    let holder = match holder {
+        Some(h) => xml_escape(h),
+        None => "<span class=\"blank\"></span>".to_string(),
+    };
+    let created = chrono::DateTime::from_timestamp(sfile.timestamp as i64, 0)
+        .map(|dt| dt.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| sfile.timestamp.to_string());
+    let (index, threshold, total) = (sfile.index, sfile.threshold, sfile.total);
+
+    let mut out = String::new();
+    out += "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n";
+    out += &format!("<title>Key share {index} of {total}</title>\n");
+    out += &format!("<style>\n{STYLE}</style>\n</head>\n<body>\n");
+    out += &format!("<h1>Safefile key share {index} of {total}</h1>\n");
+
+    out += "<table class=\"facts\">\n";
+    for (name, value) in [
+        ("Holder", holder),
+        ("Label", label.clone()),
+        ("Created", created),
+        ("Share", format!("{index} of {total}")),
+        ("Threshold", format!("any {threshold} of {total} shares")),
+        ("File ID", hex::encode(sfile.file_id)),
+        ("Fingerprint", export.fingerprint()),
+    ] {
+        out += &format!("<tr><td>{name}</td><td>{value}</td></tr>\n");
+    }
+    out += "</table>\n";
+
+    out += &format!(
+        "<p class=\"notice\">This sheet is a secret: any {threshold} of the {total} shares \
+         unlock {file}. Store it somewhere safe, apart \
+         from the other shares.</p>\n"
+    );
+
+    out += "<h2>Recovery</h2>\n<ol>\n";
+    out += &format!(
+        "<li>Gather the encrypted file and at least {threshold} of the {total} shares; \
+         the File ID on every sheet must match.</li>\n"
+    );
+    out += "<li>Type the words of each sheet into <code>safefile decrypt &lt;file&gt; \
+            &lt;output&gt; --mnemonic</code>, one share per paragraph with a blank line \
+            between shares. Mistyped words are pointed out.</li>\n";
+    out += "<li>Or save the armored text, or the text scanned from the QR code, to a \
+            file and pass that file to <code>safefile decrypt</code> as a share.</li>\n";
+    out += "</ol>\n";
+
+    out += &format!("<h2>Words ({})</h2>\n<ol class=\"words\">\n", words.len());
+    for word in &words {
+        out += &format!("<li>{word}</li>\n");
+    }
+    out += "</ol>\n";
+
+    out += "<h2>QR code</h2>\n";
+    out += &format!(
+        "<div class=\"qr\"><img alt=\"QR code of share {index}\" \
+         src=\"data:image/svg+xml;base64,{qr}\"></div>\n"
+    );
+
+    out += &format!("<h2>Armored text</h2>\n<pre>{}</pre>\n", xml_escape(&armored));
+    out += "</body>\n</html>\n";
+    Ok(out)
+}
@@ -0,0 +1,335 @@
+//! Chunked AEAD in the STREAM construction, plus a wrapper around
+//! `aes_gcm_stream` for reading legacy single-stream files.
+//!
+//! The chunks of a file are sealed with the cipher named in its header:
+//! AES-256-GCM, ChaCha20-Poly1305 for CPUs without AES instructions, or
+//! AES-256-GCM-SIV, which stays secure if a nonce is ever repeated.
+//!
+//! Every chunk is sealed with its own tag under a nonce derived from the
+//! base nonce, the chunk counter and a last-chunk flag, so reordering,
+//! dropping or truncating chunks is detected. Since chunks are independent,
+//! a batch of them is sealed or opened in parallel on the current rayon pool,
+//! and any one of them can be opened on its own.
+
+use crate::container::Cipher;
+use crate::error::Error;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::consts::U12;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, Payload};
+use aes_gcm_siv::Aes256GcmSiv;
+use aes_gcm_stream::Aes256GcmStreamDecryptor;
+use chacha20poly1305::ChaCha20Poly1305;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use zeroize::Zeroize;
+
+/// Derive the nonce of chunk `counter` from the file's base nonce.
+///
+/// The counter is XORed big-endian into bytes 3..11 and the last-chunk
+/// flag into byte 11.
+fn chunk_nonce(base: &[u8; 12], counter: u64, last: bool) -> [u8; 12] {
+    let mut nonce = *base;
+    for (n, c) in nonce[3..11].iter_mut().zip(counter.to_be_bytes()) {
+        *n ^= c;
+    }
+    nonce[11] ^= last as u8;
+    nonce
+}
+
+/// Associated data of a chunk: the trailer is bound to the last chunk only.
+fn last_chunk_aad<'a>(aad: &'a [u8], trailer: &[u8], last: bool) -> Cow<'a, [u8]> {
+    if last && !trailer.is_empty() {
+        Cow::Owned([aad, trailer].concat())
+    } else {
+        Cow::Borrowed(aad)
+    }
+}
+
+/// Chunked AEAD stream of one file, sealing or opening batches of chunks
+/// in order. Each value is used either to encrypt or to decrypt.
+pub trait ChunkCipher: Send {
+    /// Authenticate `trailer` with the last chunk, in addition to the
+    /// associated data; both sides must bind the same trailer.
+    fn bind_trailer(&mut self, trailer: &[u8]);
+
+    /// Encrypt the next chunks, returning each ciphertext with its tag
+    /// appended, in order.
+    ///
+    /// `last` marks the final element of `chunks` as the final chunk; it must
+    /// be set on the last call and on no other.
+    fn encrypt_chunks(&mut self, chunks: &[&[u8]], last: bool) -> Result<Vec<Vec<u8>>, Error>;
+
+    /// Verify and decrypt the next chunks (each ciphertext followed by its
+    /// tag), returning the plaintexts in order.
+    ///
+    /// Fails with `Error::InvalidAuthenticationTag` if any chunk was modified,
+    /// is out of order, or `last` does not match how it was encrypted. No
+    /// plaintext is returned unless every chunk verifies.
+    fn decrypt_chunks(&mut self, chunks: &[&[u8]], last: bool) -> Result<Vec<Vec<u8>>, Error>;
+
+    /// Continue at chunk `counter`, for random access to the chunks of a
+    /// file being decrypted.
+    fn seek(&mut self, counter: u64);
+}
+
+/// Create the chunk cipher of `cipher`, authenticating `aad` with every chunk.
+pub fn chunk_cipher(
+    cipher: Cipher,
+    key: [u8; 32],
+    nonce: &[u8; 12],
+    aad: &[u8],
+) -> Box<dyn ChunkCipher> {
+    match cipher {
+        Cipher::Aes256Gcm => Box::new(Stream::new(Aes256Gcm::new(&key.into()), nonce, aad)),
+        Cipher::ChaCha20Poly1305 => {
+            Box::new(Stream::new(ChaCha20Poly1305::new(&key.into()), nonce, aad))
+        }
+        Cipher::Aes256GcmSiv => Box::new(Stream::new(Aes256GcmSiv::new(&key.into()), nonce, aad)),
+    }
+}
+
+/// STREAM construction over any AEAD with 96-bit nonces.
+struct Stream<A> {
+    cipher: A,
+    nonce: [u8; 12],
+    aad: Vec<u8>,
+    trailer: Vec<u8>,
+    counter: u64,
+    finished: bool,
+}
+
+impl<A> Stream<A> {
+    fn new(cipher: A, nonce: &[u8; 12], aad: &[u8]) -> Self {
+        Self {
+            cipher,
+            nonce: *nonce,
+            aad: aad.to_vec(),
+            trailer: Vec::new(),
+            counter: 0,
+            finished: false,
+        }
+    }
+}
+
+impl<A> ChunkCipher for Stream<A>
+where
+    A: Aead + AeadCore<NonceSize = U12> + Send + Sync,
+{
+    fn bind_trailer(&mut self, trailer: &[u8]) {
+        self.trailer = trailer.to_vec();
+    }
+
+    fn encrypt_chunks(&mut self, chunks: &[&[u8]], last: bool) -> Result<Vec<Vec<u8>>, Error> {
+        if self.finished {
+            return Err(Error::InternalError {
+                details: "chunk encrypted after the last chunk".into(),
+            });
+        }
+
+        let cts = chunks
+            .par_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let last = last && i + 1 == chunks.len();
+                let nonce = chunk_nonce(&self.nonce, self.counter + i as u64, last);
+                let aad = last_chunk_aad(&self.aad, &self.trailer, last);
+                self.cipher
+                    .encrypt(
+                        &nonce.into(),
+                        Payload {
+                            msg: chunk,
+                            aad: &aad,
+                        },
+                    )
+                    .map_err(|_e| Error::InternalError {
+                        details: "chunk encryption failed".into(),
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.counter += chunks.len() as u64;
+        self.finished = last;
+        Ok(cts)
+    }
+
+    fn decrypt_chunks(&mut self, chunks: &[&[u8]], last: bool) -> Result<Vec<Vec<u8>>, Error> {
+        if self.finished {
+            return Err(Error::InvalidAuthenticationTag);
+        }
+
+        let pts = chunks
+            .par_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let last = last && i + 1 == chunks.len();
+                let nonce = chunk_nonce(&self.nonce, self.counter + i as u64, last);
+                let aad = last_chunk_aad(&self.aad, &self.trailer, last);
+                self.cipher
+                    .decrypt(
+                        &nonce.into(),
+                        Payload {
+                            msg: chunk,
+                            aad: &aad,
+                        },
+                    )
+                    .map_err(|_e| Error::InvalidAuthenticationTag)
+            })
+            .collect::<Vec<_>>();
+
+        // Collect everything first so verified plaintext can be wiped when a
+        // later chunk fails.
+        if pts.iter().any(|pt| pt.is_err()) {
+            for mut pt in pts.into_iter().flatten() {
+                pt.zeroize();
+            }
+            return Err(Error::InvalidAuthenticationTag);
+        }
+
+        self.counter += chunks.len() as u64;
+        self.finished = last;
+        Ok(pts.into_iter().flatten().collect())
+    }
+
+    fn seek(&mut self, counter: u64) {
+        self.counter = counter;
+        self.finished = false;
+    }
+}
+
+/// Decryptor for format version 1 (one GCM tag over the whole file).
+///
+/// Plaintext returned by `update` is unauthenticated until `finalize`
+/// succeeds.
+pub struct LegacyDecryptor {
+    inner: Aes256GcmStreamDecryptor,
+}
+
+impl LegacyDecryptor {
+    pub fn new(key: [u8; 32], nonce: &[u8]) -> Self {
+        Self {
+            inner: Aes256GcmStreamDecryptor::new(key, nonce),
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.inner.update(chunk)
+    }
+
+    pub fn finalize(&mut self) -> Result<Vec<u8>, Error> {
+        self.inner
+            .finalize()
+            .map_err(|_e| Error::InvalidAuthenticationTag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const NONCE: [u8; 12] = [9; 12];
+    const AAD: &[u8] = b"header";
+    const TRAILER: &[u8] = b"trailer";
+
+    /// Seal `chunks` as one file, binding `TRAILER` to the last chunk.
+    fn seal(cipher: Cipher, chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut enc = chunk_cipher(cipher, KEY, &NONCE, AAD);
+        enc.bind_trailer(TRAILER);
+        enc.encrypt_chunks(chunks, true).unwrap()
+    }
+
+    /// Open `cts` as one file whose last chunk is bound to `trailer`.
+    fn open(cipher: Cipher, cts: &[Vec<u8>], trailer: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let mut dec = chunk_cipher(cipher, KEY, &NONCE, AAD);
+        dec.bind_trailer(trailer);
+        let cts: Vec<&[u8]> = cts.iter().map(Vec::as_slice).collect();
+        dec.decrypt_chunks(&cts, true)
+    }
+
+    #[test]
+    fn round_trip() {
+        let chunks: [&[u8]; 3] = [b"first", b"second", b""];
+        for cipher in Cipher::ALL {
+            let cts = seal(cipher, &chunks);
+            assert_eq!(open(cipher, &cts, TRAILER).unwrap(), chunks);
+        }
+    }
+
+    #[test]
+    fn round_trip_in_batches() {
+        for cipher in Cipher::ALL {
+            let mut enc = chunk_cipher(cipher, KEY, &NONCE, AAD);
+            let mut cts = enc.encrypt_chunks(&[b"one", b"two"], false).unwrap();
+            cts.extend(enc.encrypt_chunks(&[b"three"], true).unwrap());
+
+            let mut dec = chunk_cipher(cipher, KEY, &NONCE, AAD);
+            let mut pts = dec.decrypt_chunks(&[&cts[0]], false).unwrap();
+            pts.extend(dec.decrypt_chunks(&[&cts[1], &cts[2]], true).unwrap());
+            assert_eq!(pts, [b"one".as_slice(), b"two", b"three"]);
+        }
+    }
+
+    #[test]
+    fn flipped_byte_is_rejected() {
+        for cipher in Cipher::ALL {
+            let mut cts = seal(cipher, &[b"first", b"second"]);
+            cts[0][2] ^= 1;
+            assert!(matches!(
+                open(cipher, &cts, TRAILER),
+                Err(Error::InvalidAuthenticationTag)
+            ));
+        }
+    }
+
+    #[test]
+    fn swapped_chunks_are_rejected() {
+        for cipher in Cipher::ALL {
+            let mut cts = seal(cipher, &[b"first", b"other", b"last"]);
+            cts.swap(0, 1);
+            assert!(matches!(
+                open(cipher, &cts, TRAILER),
+                Err(Error::InvalidAuthenticationTag)
+            ));
+        }
+    }
+
+    #[test]
+    fn truncation_is_rejected() {
+        for cipher in Cipher::ALL {
+            let cts = seal(cipher, &[b"first", b"second", b"last"]);
+            // Dropping whole chunks leaves a chunk not sealed as the last one.
+            assert!(matches!(
+                open(cipher, &cts[..2], TRAILER),
+                Err(Error::InvalidAuthenticationTag)
+            ));
+        }
+    }
+
+    #[test]
+    fn tampered_trailer_is_rejected() {
+        for cipher in Cipher::ALL {
+            let cts = seal(cipher, &[b"first", b"last"]);
+            assert!(matches!(
+                open(cipher, &cts, b"trailes"),
+                Err(Error::InvalidAuthenticationTag)
+            ));
+        }
+    }
+
+    #[test]
+    fn seek_opens_a_single_chunk() {
+        for cipher in Cipher::ALL {
+            let cts = seal(cipher, &[b"first", b"second", b"last"]);
+            let mut dec = chunk_cipher(cipher, KEY, &NONCE, AAD);
+            dec.bind_trailer(TRAILER);
+
+            dec.seek(2);
+            assert_eq!(dec.decrypt_chunks(&[&cts[2]], true).unwrap(), [b"last"]);
+            dec.seek(1);
+            assert_eq!(dec.decrypt_chunks(&[&cts[1]], false).unwrap(), [b"second"]);
+            // A chunk opened at the wrong position does not verify.
+            dec.seek(0);
+            assert!(dec.decrypt_chunks(&[&cts[1]], false).is_err());
+        }
+    }
+}
@@ -21,6 +21,14 @@ pub fn us_to_human_readable(us: u128) -> String {
     }
 }
 
+/// Escape text for use in HTML and XML content and attribute values.
+pub fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
 pub struct Timer {
     start: std::time::Instant,
     end: Option<std::time::Instant>,
@@ -28,6 +36,12 @@ pub struct Timer {
     pub duration_us: u128,
 }
 
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Timer {
     pub fn new() -> Self {
         Self {
@@ -38,7 +38,7 @@ impl DecryptionPanel {
     pub fn get_params(&self) -> (std::path::PathBuf, std::path::PathBuf, Vec<std::path::PathBuf>) {
         let input = self.input_file_row.value().clone();
         let output = self.output_file_row.value().clone();
-        let shares = self.shares_selector.files().iter().cloned().collect();
+        let shares = self.shares_selector.files().to_vec();
         (input, output, shares)
     }
 
@@ -52,7 +52,7 @@ impl DecryptionPanel {
                 let dialog = FileDialog::new();
 
                 let file = dialog.pick_file();
-                if file == None {
+                if file.is_none() {
                     return PanelCommand::None;
                 }
                 let file = file.unwrap();
@@ -71,13 +71,18 @@ impl SafeFileApp {
                 iced::Task::perform(
                     async move {
                         match tokio::task::spawn_blocking(move || {
-                            safefile::ops::encrypt_and_split(
-                                &input,
-                                &output,
-                                &outdir,
+                            let split = safefile::ops::ShareOptions {
+                                outdir: Some(&outdir),
                                 shares,
                                 threshold,
+                                recipients: &[],
+                            };
+                            safefile::ops::encrypt_and_split(
+                                safefile::ops::Input::File(&input),
+                                &output,
+                                &split,
                                 label.as_deref(),
+                                &safefile::crypto::CryptoOptions::default(),
                                 |_, _| {}, // TODO: Progress bar
                             )
                         }).await {
@@ -98,8 +103,10 @@ impl SafeFileApp {
                             let share_refs: Vec<&std::path::Path> = shares.iter().map(|p| p.as_path()).collect();
                             safefile::ops::decrypt_and_reconstruct(
                                 &input,
-                                &output,
+                                safefile::ops::Output::File(&output),
                                 &share_refs,
+                                &[],
+                                &safefile::crypto::CryptoOptions::default(),
                                 |_, _| {}, // TODO: Progress bar
                             )
                         }).await {
@@ -163,10 +170,10 @@ impl SafeFileApp {
         let main_panel = row![
             self.encryption_panel
                 .view()
-                .map(|m| Message::EncryptionPanel(m)),
+                .map(Message::EncryptionPanel),
             self.decryption_panel
                 .view()
-                .map(|m| Message::DecryptionPanel(m)),
+                .map(Message::DecryptionPanel),
         ]
         .spacing(20)
         .width(Length::Fill)